@@ -1,20 +1,146 @@
+mod options;
+
+use options::{Firmware, Mode, Options, OptionsError};
+use std::{
+    path::{Path, PathBuf},
+    process::{self, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Must match `EXIT_PORT` and `QemuExitCode` in the kernel's testing module. QEMU exits with
+// `(code << 1) | 1` for whatever the kernel writes to the exit port.
+const TEST_EXIT_PORT: &str = "0xf4";
+const TEST_SUCCESS_STATUS: i32 = (0x10 << 1) | 1;
+const TEST_TIMEOUT: Duration = Duration::from_secs(300);
+
 fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(OptionsError::Help) => {
+            println!("{}", options::USAGE);
+            return;
+        }
+        Err(error) => {
+            eprintln!("{error}\n\n{}", options::USAGE);
+            process::exit(2);
+        }
+    };
+
+    match options.mode {
+        Mode::Run => process::exit(run(&options)),
+        Mode::Test => process::exit(run_tests(&options)),
+    }
+}
+
+fn run(options: &Options) -> i32 {
     // read env variables that were set in build script
-    let uefi_path = env!("UEFI_PATH");
-    let bios_path = env!("BIOS_PATH");
+    let image = match options.firmware {
+        Firmware::Uefi => env!("UEFI_PATH"),
+        Firmware::Bios => env!("BIOS_PATH"),
+    };
+
+    let mut cmd = qemu(options, image);
+    if options.gdb {
+        eprintln!("waiting for gdb on localhost:1234");
+    }
+    let status = cmd.spawn().unwrap().wait().unwrap();
+
+    status.code().unwrap_or(1)
+}
+
+// Boots the test kernel headless, with its serial output on our stdout, and turns the exit code
+// it reports through the isa-debug-exit device into ours
+fn run_tests(options: &Options) -> i32 {
+    let kernel = build_test_kernel();
+
+    let image = match options.firmware {
+        Firmware::Uefi => {
+            let image = kernel.with_extension("uefi.img");
+            bootloader::UefiBoot::new(&kernel)
+                .set_ramdisk(Path::new(env!("RAMDISK_PATH")))
+                .create_disk_image(&image)
+                .unwrap();
+            image
+        }
+        Firmware::Bios => {
+            let image = kernel.with_extension("bios.img");
+            bootloader::BiosBoot::new(&kernel)
+                .set_ramdisk(Path::new(env!("RAMDISK_PATH")))
+                .create_disk_image(&image)
+                .unwrap();
+            image
+        }
+    };
 
-    // choose whether to start the UEFI or BIOS image
-    let uefi = true;
+    let mut cmd = qemu(options, image.to_str().unwrap());
+    cmd.arg("-device").arg(format!(
+        "isa-debug-exit,iobase={TEST_EXIT_PORT},iosize=0x04"
+    ));
 
-    let mut cmd = std::process::Command::new("qemu-system-x86_64");
-    if uefi {
+    let mut child = cmd.spawn().unwrap();
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > TEST_TIMEOUT {
+            child.kill().unwrap();
+            eprintln!("tests timed out after {}s", TEST_TIMEOUT.as_secs());
+            return 1;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    match status.code() {
+        Some(TEST_SUCCESS_STATUS) => 0,
+        Some(code) => {
+            eprintln!("tests failed (qemu exited with {code})");
+            1
+        }
+        None => {
+            eprintln!("qemu was killed by a signal");
+            1
+        }
+    }
+}
+
+// Builds the kernel's test binary and returns its path, which cargo only reports in its json
+// output
+fn build_test_kernel() -> PathBuf {
+    let kernel_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("kernel");
+    let output = Command::new(env!("CARGO"))
+        .args(["test", "--no-run", "--message-format=json"])
+        .current_dir(kernel_dir)
+        .output()
+        .unwrap();
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        process::exit(1);
+    }
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let executable = stdout
+        .lines()
+        .filter(|line| line.contains(r#""test":true"#))
+        .find_map(|line| {
+            let start = line.find(r#""executable":""#)? + r#""executable":""#.len();
+            let end = start + line[start..].find('"')?;
+            Some(&line[start..end])
+        })
+        .expect("cargo didn't report a test executable for the kernel");
+
+    PathBuf::from(executable)
+}
+
+fn qemu(options: &Options, image: &str) -> Command {
+    let mut cmd = Command::new("qemu-system-x86_64");
+    if options.firmware == Firmware::Uefi {
         cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
-        cmd.arg("-drive")
-            .arg(format!("format=raw,file={uefi_path}"));
-    } else {
-        cmd.arg("-drive")
-            .arg(format!("format=raw,file={bios_path}"));
     }
-    let mut child = cmd.spawn().unwrap();
-    child.wait().unwrap();
+    cmd.arg("-drive").arg(format!("format=raw,file={image}"));
+    options.apply(&mut cmd);
+
+    cmd
 }
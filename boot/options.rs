@@ -0,0 +1,241 @@
+use std::{fmt, process::Command};
+
+pub const USAGE: &str = "\
+usage: os-qemu [test] [options] [-- <qemu args>...]
+
+Boots the kernel in QEMU, or with `test`, builds and runs the kernel's tests headless.
+
+options:
+    --uefi                boot through OVMF (default)
+    --bios                boot through legacy BIOS
+    -m, --memory <size>   guest memory, in QEMU's format (e.g. 512M, 2G)
+    --cpus <count>        number of CPUs
+    --machine <type>      machine type, pc or q35
+    --headless            don't open a display window
+    --serial <device>     where COM1 goes, in QEMU's format (e.g. stdio, file:serial.log, none)
+    --gdb                 start a GDB stub on localhost:1234 and wait for it before booting
+    -h, --help            print this message
+    --                    pass everything after it straight to QEMU";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Run,
+    Test,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    Uefi,
+    Bios,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Machine {
+    Pc,
+    Q35,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub mode: Mode,
+    pub firmware: Firmware,
+    pub memory: Option<String>,
+    pub cpus: Option<u32>,
+    pub machine: Option<Machine>,
+    pub headless: bool,
+    pub serial: Option<String>,
+    pub gdb: bool,
+    pub qemu_args: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum OptionsError {
+    Help,
+    MissingValue(String),
+    InvalidValue(String, String),
+    Unknown(String),
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Help => write!(f, "{USAGE}"),
+            Self::MissingValue(flag) => write!(f, "{flag} needs a value"),
+            Self::InvalidValue(flag, value) => write!(f, "invalid value for {flag}: {value}"),
+            Self::Unknown(arg) => write!(f, "unknown argument: {arg}"),
+        }
+    }
+}
+
+impl Options {
+    /// Parses the launcher's arguments, not including the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, OptionsError> {
+        let mut options = Self {
+            mode: Mode::Run,
+            firmware: Firmware::Uefi,
+            memory: None,
+            cpus: None,
+            machine: None,
+            headless: false,
+            serial: None,
+            gdb: false,
+            qemu_args: vec![],
+        };
+
+        let mut args = args.into_iter().peekable();
+        if args.peek().map(String::as_str) == Some("test") {
+            args.next();
+            options.mode = Mode::Test;
+        }
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| OptionsError::MissingValue(arg.clone()))
+            };
+
+            match arg.as_str() {
+                "--uefi" => options.firmware = Firmware::Uefi,
+                "--bios" => options.firmware = Firmware::Bios,
+                "-m" | "--memory" => options.memory = Some(value()?),
+                "--cpus" => {
+                    let cpus = value()?;
+                    match cpus.parse() {
+                        Ok(cpus) if cpus > 0 => options.cpus = Some(cpus),
+                        _ => return Err(OptionsError::InvalidValue(arg, cpus)),
+                    }
+                }
+                "--machine" => {
+                    let machine = value()?;
+                    options.machine = match machine.as_str() {
+                        "pc" => Some(Machine::Pc),
+                        "q35" => Some(Machine::Q35),
+                        _ => return Err(OptionsError::InvalidValue(arg, machine)),
+                    };
+                }
+                "--headless" => options.headless = true,
+                "--serial" => options.serial = Some(value()?),
+                "--gdb" => options.gdb = true,
+                "-h" | "--help" => return Err(OptionsError::Help),
+                "--" => {
+                    options.qemu_args.extend(args);
+                    break;
+                }
+                _ => return Err(OptionsError::Unknown(arg)),
+            }
+        }
+
+        // tests report over serial and nobody is there to look at a window
+        if options.mode == Mode::Test {
+            options.headless = true;
+        }
+
+        Ok(options)
+    }
+
+    /// Adds everything but the firmware and drive to a QEMU command line.
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(memory) = &self.memory {
+            cmd.arg("-m").arg(memory);
+        }
+        if let Some(cpus) = self.cpus {
+            cmd.arg("-smp").arg(cpus.to_string());
+        }
+        match self.machine {
+            Some(Machine::Pc) => {
+                cmd.arg("-machine").arg("pc");
+            }
+            Some(Machine::Q35) => {
+                cmd.arg("-machine").arg("q35");
+            }
+            None => {}
+        }
+        if self.headless {
+            cmd.arg("-display").arg("none");
+        }
+        // without a display, serial is the only way to see anything
+        match &self.serial {
+            Some(serial) => {
+                cmd.arg("-serial").arg(serial);
+            }
+            None if self.headless => {
+                cmd.arg("-serial").arg("stdio");
+            }
+            None => {}
+        }
+        if self.gdb {
+            cmd.arg("-s").arg("-S");
+        }
+        cmd.args(&self.qemu_args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, OptionsError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults_to_running_under_uefi() {
+        let options = parse(&[]).unwrap();
+
+        assert_eq!(options.mode, Mode::Run);
+        assert_eq!(options.firmware, Firmware::Uefi);
+        assert!(!options.headless);
+        assert!(options.qemu_args.is_empty());
+    }
+
+    #[test]
+    fn test_forces_headless() {
+        let options = parse(&["test", "--bios"]).unwrap();
+
+        assert_eq!(options.mode, Mode::Test);
+        assert_eq!(options.firmware, Firmware::Bios);
+        assert!(options.headless);
+    }
+
+    #[test]
+    fn flags_missing_their_value_are_rejected() {
+        for flag in ["-m", "--memory", "--cpus", "--machine", "--serial"] {
+            match parse(&[flag]) {
+                Err(OptionsError::MissingValue(missing)) => assert_eq!(missing, flag),
+                other => panic!("{flag}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for (flag, value) in [("--cpus", "0"), ("--cpus", "two"), ("--machine", "foo")] {
+            match parse(&[flag, value]) {
+                Err(OptionsError::InvalidValue(invalid, rejected)) => {
+                    assert_eq!((invalid.as_str(), rejected.as_str()), (flag, value))
+                }
+                other => panic!("{flag} {value}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn everything_after_double_dash_goes_to_qemu() {
+        let options = parse(&["--cpus", "2", "--", "--gdb", "-d", "int"]).unwrap();
+
+        assert_eq!(options.cpus, Some(2));
+        assert!(!options.gdb);
+        assert_eq!(options.qemu_args, ["--gdb", "-d", "int"]);
+    }
+
+    #[test]
+    fn unknown_arguments_and_help_are_reported() {
+        assert!(matches!(parse(&["--fast"]), Err(OptionsError::Unknown(arg)) if arg == "--fast"));
+        assert!(matches!(parse(&["-h"]), Err(OptionsError::Help)));
+        // `test` only means test mode in first position
+        assert!(matches!(
+            parse(&["--bios", "test"]),
+            Err(OptionsError::Unknown(_))
+        ));
+    }
+}
@@ -1,4 +1,8 @@
-use std::path::PathBuf;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -7,19 +11,104 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // pack the data directory into a tar archive, which the bootloader hands the kernel as its
+    // ramdisk
+    let data_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("data");
+    let ramdisk_path = out_dir.join("ramdisk.tar");
+    write_archive(&data_dir, &ramdisk_path).unwrap();
+    println!("cargo:rerun-if-changed={}", data_dir.display());
+    println!("cargo:rerun-if-changed=build.rs");
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
+    println!("cargo:rustc-env=RAMDISK_PATH={}", ramdisk_path.display());
+}
+
+// Writes every file under `root` into a ustar archive, with paths relative to `root`. Entries
+// are sorted and timestamps zeroed so the archive only changes when the files do.
+fn write_archive(root: &Path, archive_path: &Path) -> io::Result<()> {
+    let mut files = vec![];
+    collect_files(root, &mut files)?;
+    files.sort();
+
+    let mut archive = File::create(archive_path)?;
+    for path in files {
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_str()
+            .expect("ramdisk paths must be valid UTF-8")
+            .replace('\\', "/");
+        let data = fs::read(&path)?;
+
+        archive.write_all(&tar_header(&name, data.len() as u64))?;
+        archive.write_all(&data)?;
+        // file data is padded out to whole blocks
+        let padding = (512 - data.len() % 512) % 512;
+        archive.write_all(&vec![0; padding])?;
+    }
+
+    // two empty blocks mark the end of the archive
+    archive.write_all(&[0; 1024])
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn tar_header(path: &str, size: u64) -> [u8; 512] {
+    let mut header = [0; 512];
+
+    // names past 100 bytes are split at a slash, with the front going in the prefix field
+    let (prefix, name) = match path.len() {
+        0..=100 => ("", path),
+        _ => path
+            .char_indices()
+            .filter(|&(i, char)| char == '/' && i <= 155 && path.len() - i - 1 <= 100)
+            .map(|(i, _)| (&path[..i], &path[i + 1..]))
+            .next()
+            .unwrap_or_else(|| panic!("{path} is too long for a tar archive")),
+    };
+
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[108..115].copy_from_slice(b"0000000");
+    header[116..123].copy_from_slice(b"0000000");
+    header[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // the checksum is taken with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+
+    header
 }
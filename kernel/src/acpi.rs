@@ -0,0 +1,193 @@
+use crate::mem;
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr, slice};
+use thiserror::Error;
+use x86_64::PhysAddr;
+
+pub type AcpiResult<T> = core::result::Result<T, AcpiError>;
+
+#[derive(Debug, Error)]
+pub enum AcpiError {
+    #[error("RSDP signature or checksum is invalid")]
+    InvalidRsdp,
+    #[error("{0} table checksum is invalid")]
+    InvalidChecksum(&'static str),
+    #[error("{0} table not found")]
+    TableNotFound(&'static str),
+}
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &str = "APIC";
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // only present from revision 2 onwards
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// The parts of the Multiple APIC Description Table the interrupt controllers need.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub local_apics: Vec<LocalApicInfo>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicInfo {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// Describes an ISA IRQ that isn't identity mapped to a global system interrupt, or that doesn't
+/// use the ISA default of active high and edge triggered.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl Madt {
+    /// Finds and parses the MADT through the RSDP the bootloader handed us.
+    ///
+    /// This function is unsafe because the caller must guarantee that `rsdp_address` points at
+    /// the firmware's RSDP, and that physical memory is mapped.
+    pub unsafe fn parse(rsdp_address: PhysAddr) -> AcpiResult<Self> {
+        let madt = find_table(rsdp_address, MADT_SIGNATURE)?;
+        let header = read::<SdtHeader>(madt);
+        let local_apic_address = read::<u32>(madt + size_of::<SdtHeader>());
+
+        let mut result = Self {
+            local_apic_address: PhysAddr::new(local_apic_address as u64),
+            local_apics: vec![],
+            io_apics: vec![],
+            overrides: vec![],
+        };
+
+        // entries follow the local APIC address and a flags field
+        let end = madt + header.length as u64;
+        let mut entry = madt + size_of::<SdtHeader>() + 8u64;
+        while entry < end {
+            let kind = read::<u8>(entry);
+            let length = read::<u8>(entry + 1u64);
+            match kind {
+                0 => result.local_apics.push(LocalApicInfo {
+                    processor_id: read(entry + 2u64),
+                    apic_id: read(entry + 3u64),
+                    enabled: read::<u32>(entry + 4u64) & 1 != 0,
+                }),
+                1 => result.io_apics.push(IoApicInfo {
+                    id: read(entry + 2u64),
+                    address: PhysAddr::new(read::<u32>(entry + 4u64) as u64),
+                    gsi_base: read(entry + 8u64),
+                }),
+                2 => {
+                    let flags = read::<u16>(entry + 8u64);
+                    result.overrides.push(InterruptOverride {
+                        irq: read(entry + 3u64),
+                        gsi: read(entry + 4u64),
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    })
+                }
+                5 => result.local_apic_address = PhysAddr::new(read(entry + 4u64)),
+                _ => {}
+            }
+
+            // a zero length entry would loop forever, so treat it as the end of a corrupt table
+            if length == 0 {
+                break;
+            }
+            entry += length as u64;
+        }
+
+        Ok(result)
+    }
+}
+
+/// Looks up an ACPI table by signature through the XSDT, or the RSDT on ACPI 1.0 firmware.
+unsafe fn find_table(rsdp_address: PhysAddr, signature: &'static str) -> AcpiResult<PhysAddr> {
+    let rsdp = read::<Rsdp>(rsdp_address);
+    if &rsdp.signature != RSDP_SIGNATURE || !checksum(rsdp_address, 20) {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    let (root, entry_size) = match rsdp.revision {
+        0 => (PhysAddr::new(rsdp.rsdt_address as u64), 4),
+        _ => (PhysAddr::new(rsdp.xsdt_address), 8),
+    };
+    let root_header = read::<SdtHeader>(root);
+    if !checksum(root, root_header.length as usize) {
+        return Err(AcpiError::InvalidChecksum(if entry_size == 4 {
+            "RSDT"
+        } else {
+            "XSDT"
+        }));
+    }
+
+    let entry_count = (root_header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    for i in 0..entry_count {
+        let entry = root + size_of::<SdtHeader>() + (i * entry_size) as u64;
+        let table = match entry_size {
+            4 => PhysAddr::new(read::<u32>(entry) as u64),
+            _ => PhysAddr::new(read::<u64>(entry)),
+        };
+
+        let header = read::<SdtHeader>(table);
+        if &header.signature == signature.as_bytes() {
+            if !checksum(table, header.length as usize) {
+                return Err(AcpiError::InvalidChecksum(signature));
+            }
+
+            return Ok(table);
+        }
+    }
+
+    Err(AcpiError::TableNotFound(signature))
+}
+
+// Firmware tables make no alignment promises, so every field goes through an unaligned read
+unsafe fn read<T: Copy>(address: PhysAddr) -> T {
+    ptr::read_unaligned(mem::physical_to_virtual(address).as_ptr())
+}
+
+// ACPI structures are valid when all of their bytes sum to zero
+unsafe fn checksum(address: PhysAddr, length: usize) -> bool {
+    let bytes = slice::from_raw_parts(mem::physical_to_virtual(address).as_ptr::<u8>(), length);
+
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
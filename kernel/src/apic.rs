@@ -0,0 +1,243 @@
+use crate::{
+    acpi::{AcpiError, InterruptOverride, Madt},
+    mem::{
+        vmm::{self, Caching, Protection},
+        MemoryError,
+    },
+    pic::PIC_1_OFFSET,
+};
+use alloc::vec::Vec;
+use core::ptr;
+use thiserror::Error;
+use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+pub type ApicResult<T> = core::result::Result<T, ApicError>;
+
+#[derive(Debug, Error)]
+pub enum ApicError {
+    #[error(transparent)]
+    Acpi(#[from] AcpiError),
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+    #[error("MADT lists no I/O APIC")]
+    NoIoApic,
+}
+
+/// Finds the local and I/O APICs through the MADT, maps their registers and enables the local
+/// APIC. Every redirection entry starts out masked.
+///
+/// This function is unsafe because the caller must guarantee that `rsdp_address` points at the
+/// firmware's RSDP.
+pub unsafe fn initialize(rsdp_address: PhysAddr) -> ApicResult<Apic> {
+    let madt = Madt::parse(rsdp_address)?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let local_base = map_registers(madt.local_apic_address, 0x1000)?;
+    let mut local = LocalApic::new(local_base);
+    local.enable();
+
+    let io_apics = madt
+        .io_apics
+        .iter()
+        .map(|info| {
+            let base = map_registers(info.address, 0x20)?;
+            let mut io_apic = IoApic::new(base, info.gsi_base);
+            (0..io_apic.redirection_count()).for_each(|i| {
+                io_apic.set_redirection(
+                    info.gsi_base + i,
+                    RedirectionEntry {
+                        masked: true,
+                        ..RedirectionEntry::default()
+                    },
+                )
+            });
+
+            Ok(io_apic)
+        })
+        .collect::<ApicResult<Vec<IoApic>>>()?;
+
+    Ok(Apic {
+        local,
+        io_apics,
+        overrides: madt.overrides,
+    })
+}
+
+fn map_registers(address: PhysAddr, size: u64) -> ApicResult<VirtAddr> {
+    let base = vmm::map_physical(address, size, Protection::READ_WRITE, Caching::Uncached)?;
+
+    Ok(base)
+}
+
+/// The bootstrap processor's local APIC together with the I/O APICs ISA IRQs are routed through.
+pub struct Apic {
+    local: LocalApic,
+    io_apics: Vec<IoApic>,
+    overrides: Vec<InterruptOverride>,
+}
+
+impl Apic {
+    pub fn local(&mut self) -> &mut LocalApic {
+        &mut self.local
+    }
+
+    pub fn mask(&mut self, irq: u8) {
+        self.route(irq, true)
+    }
+
+    pub fn unmask(&mut self, irq: u8) {
+        self.route(irq, false)
+    }
+
+    pub fn end_of_interrupt(&mut self) {
+        self.local.end_of_interrupt()
+    }
+
+    // Programs the redirection entry for an ISA IRQ so it arrives at the same vector the PIC
+    // would have used, honouring any override from the MADT
+    fn route(&mut self, irq: u8, masked: bool) {
+        let (gsi, entry) = match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (
+                o.gsi,
+                RedirectionEntry {
+                    active_low: o.active_low,
+                    level_triggered: o.level_triggered,
+                    ..RedirectionEntry::default()
+                },
+            ),
+            None => (irq as u32, RedirectionEntry::default()),
+        };
+        let destination = self.local.id();
+
+        if let Some(io_apic) = self.io_apics.iter_mut().find(|a| a.handles(gsi)) {
+            io_apic.set_redirection(
+                gsi,
+                RedirectionEntry {
+                    vector: PIC_1_OFFSET + irq,
+                    masked,
+                    destination,
+                    ..entry
+                },
+            );
+        }
+    }
+}
+
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    const ID: usize = 0x20;
+    const TASK_PRIORITY: usize = 0x80;
+    const END_OF_INTERRUPT: usize = 0xb0;
+    const SPURIOUS_VECTOR: usize = 0xf0;
+
+    fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    fn enable(&mut self) {
+        unsafe {
+            let mut apic_base = Msr::new(IA32_APIC_BASE);
+            let value = apic_base.read();
+            apic_base.write(value | APIC_GLOBAL_ENABLE);
+
+            self.write(Self::TASK_PRIORITY, 0);
+            self.write(Self::SPURIOUS_VECTOR, 0x100 | SPURIOUS_VECTOR as u32);
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        unsafe { (self.read(Self::ID) >> 24) as u8 }
+    }
+
+    pub fn end_of_interrupt(&mut self) {
+        unsafe { self.write(Self::END_OF_INTERRUPT, 0) }
+    }
+
+    pub unsafe fn read(&self, register: usize) -> u32 {
+        ptr::read_volatile((self.base + register).as_ptr())
+    }
+
+    pub unsafe fn write(&mut self, register: usize, value: u32) {
+        ptr::write_volatile((self.base + register).as_mut_ptr(), value)
+    }
+}
+
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirection_count: u32,
+}
+
+impl IoApic {
+    const VERSION: u32 = 0x01;
+    const REDIRECTION_TABLE: u32 = 0x10;
+
+    fn new(base: VirtAddr, gsi_base: u32) -> Self {
+        let mut io_apic = Self {
+            base,
+            gsi_base,
+            redirection_count: 0,
+        };
+        io_apic.redirection_count = unsafe { ((io_apic.read(Self::VERSION) >> 16) & 0xff) + 1 };
+
+        io_apic
+    }
+
+    pub fn redirection_count(&self) -> u32 {
+        self.redirection_count
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.redirection_count).contains(&gsi)
+    }
+
+    pub fn set_redirection(&mut self, gsi: u32, entry: RedirectionEntry) {
+        let register = Self::REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        let value = entry.as_u64();
+        unsafe {
+            self.write(register, value as u32);
+            self.write(register + 1, (value >> 32) as u32);
+        }
+    }
+
+    // The I/O APIC only exposes an index and a data window, every register goes through them
+    unsafe fn read(&mut self, register: u32) -> u32 {
+        ptr::write_volatile(self.base.as_mut_ptr::<u32>(), register);
+        ptr::read_volatile((self.base + 0x10u64).as_ptr())
+    }
+
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        ptr::write_volatile(self.base.as_mut_ptr::<u32>(), register);
+        ptr::write_volatile((self.base + 0x10u64).as_mut_ptr(), value)
+    }
+}
+
+/// An I/O APIC redirection table entry, delivering to a single local APIC in fixed mode.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    pub destination: u8,
+}
+
+impl RedirectionEntry {
+    pub fn as_u64(&self) -> u64 {
+        self.vector as u64
+            | (self.active_low as u64) << 13
+            | (self.level_triggered as u64) << 15
+            | (self.masked as u64) << 16
+            | (self.destination as u64) << 56
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{serial, terminal};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static TERMINAL_ENABLED: AtomicBool = AtomicBool::new(true);
+static SERIAL_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Somewhere `print!` output can go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Terminal,
+    Serial,
+}
+
+impl Sink {
+    fn enabled(&self) -> &'static AtomicBool {
+        match self {
+            Self::Terminal => &TERMINAL_ENABLED,
+            Self::Serial => &SERIAL_ENABLED,
+        }
+    }
+}
+
+/// Turns a sink on or off. Both start out on.
+pub fn set_enabled(sink: Sink, enabled: bool) {
+    sink.enabled().store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled(sink: Sink) -> bool {
+    sink.enabled().load(Ordering::Relaxed)
+}
+
+/// Writes to every enabled sink. The terminal is skipped until it's been initialized, so output
+/// from early in boot only shows up on serial.
+#[doc(hidden)]
+pub fn print(args: fmt::Arguments) {
+    if is_enabled(Sink::Serial) {
+        serial::print(args);
+    }
+    if is_enabled(Sink::Terminal) && terminal::is_initialized() {
+        terminal::print(args);
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    () => ($crate::console::print(format_args!("")));
+    ($($arg:tt)*) => ($crate::console::print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,127 @@
+use core::{arch::asm, fmt};
+use x86_64::registers::{
+    control::{Cr0, Cr2, Cr3, Cr4},
+    segmentation::{Segment, CS, SS},
+};
+
+/// A snapshot of the executing CPU's register state.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub cs: u16,
+    pub ss: u16,
+}
+
+impl Registers {
+    /// Captures the registers at the call site.
+    ///
+    /// `rdi` and `rax` are used as scratch while capturing, so `rdi` always holds the address of
+    /// the snapshot and `rip` points into this function.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut registers = Self::default();
+        unsafe {
+            asm!(
+                "mov [rdi + 0x00], rax",
+                "mov [rdi + 0x08], rbx",
+                "mov [rdi + 0x10], rcx",
+                "mov [rdi + 0x18], rdx",
+                "mov [rdi + 0x20], rsi",
+                "mov [rdi + 0x28], rdi",
+                "mov [rdi + 0x30], rbp",
+                "mov [rdi + 0x38], rsp",
+                "mov [rdi + 0x40], r8",
+                "mov [rdi + 0x48], r9",
+                "mov [rdi + 0x50], r10",
+                "mov [rdi + 0x58], r11",
+                "mov [rdi + 0x60], r12",
+                "mov [rdi + 0x68], r13",
+                "mov [rdi + 0x70], r14",
+                "mov [rdi + 0x78], r15",
+                "lea rax, [rip]",
+                "mov [rdi + 0x80], rax",
+                "pushfq",
+                "pop rax",
+                "mov [rdi + 0x88], rax",
+                in("rdi") &mut registers as *mut Self,
+                out("rax") _,
+            );
+        }
+
+        registers.cr0 = Cr0::read_raw();
+        registers.cr2 = Cr2::read_raw();
+        registers.cr3 = Cr3::read_raw().0.start_address().as_u64();
+        registers.cr4 = Cr4::read_raw();
+        registers.cs = CS::get_reg().0;
+        registers.ss = SS::get_reg().0;
+
+        registers
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "rax {:016x}  rbx {:016x}  rcx {:016x}",
+            self.rax, self.rbx, self.rcx
+        )?;
+        writeln!(
+            f,
+            "rdx {:016x}  rsi {:016x}  rdi {:016x}",
+            self.rdx, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "rbp {:016x}  rsp {:016x}  r8  {:016x}",
+            self.rbp, self.rsp, self.r8
+        )?;
+        writeln!(
+            f,
+            "r9  {:016x}  r10 {:016x}  r11 {:016x}",
+            self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "r12 {:016x}  r13 {:016x}  r14 {:016x}",
+            self.r12, self.r13, self.r14
+        )?;
+        writeln!(
+            f,
+            "r15 {:016x}  rip {:016x}  rfl {:016x}",
+            self.r15, self.rip, self.rflags
+        )?;
+        writeln!(
+            f,
+            "cr0 {:016x}  cr2 {:016x}  cr3 {:016x}",
+            self.cr0, self.cr2, self.cr3
+        )?;
+        write!(
+            f,
+            "cr4 {:016x}  cs  {:04x}  ss  {:04x}",
+            self.cr4, self.cs, self.ss
+        )
+    }
+}
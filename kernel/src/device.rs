@@ -1,16 +1,197 @@
-use x86_64::instructions::port::Port;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
 
-struct PciDevice<'pci> {
+// The legacy configuration mechanism, an address written to one port picks the register the
+// other one reads or writes
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Offsets into a function's configuration space header
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const PROG_IF: u16 = 0x09;
+const SUBCLASS: u16 = 0x0a;
+const CLASS: u16 = 0x0b;
+const HEADER_TYPE: u16 = 0x0e;
+const INTERRUPT_LINE: u16 = 0x3c;
+const INTERRUPT_PIN: u16 = 0x3d;
+
+const MULTIFUNCTION: u8 = 0x80;
+// No vendor has this ID, it's what reads of an empty slot return
+const NO_VENDOR: u16 = 0xffff;
+
+/// Configuration space access through the legacy I/O ports, which every PC chipset has.
+pub static PCI_CONFIG: PortConfigAccess = PortConfigAccess::new();
+
+pub struct PciDevice<'pci> {
     pub bus: &'pci PciBus<'pci>,
     pub num: u8,
 }
 
-struct PciBus<'pci> {
+impl<'pci> PciDevice<'pci> {
+    pub fn function(&self, num: u8) -> PciFunction<'pci> {
+        PciFunction {
+            bus: self.bus,
+            device: self.num,
+            num,
+        }
+    }
+
+    /// The functions that are present, none if the slot is empty.
+    pub fn functions(&self) -> impl Iterator<Item = PciFunction<'pci>> {
+        let first = self.function(0);
+        let count = match first.is_present() {
+            true if first.is_multifunction() => 8,
+            true => 1,
+            false => 0,
+        };
+        let (bus, device) = (self.bus, self.num);
+
+        (0..count)
+            .map(move |num| PciFunction { bus, device, num })
+            .filter(PciFunction::is_present)
+    }
+}
+
+/// One function of a device, each with its own configuration space.
+pub struct PciFunction<'pci> {
+    pub bus: &'pci PciBus<'pci>,
+    pub device: u8,
+    pub num: u8,
+}
+
+impl<'pci> PciFunction<'pci> {
+    pub fn is_present(&self) -> bool {
+        self.vendor_id() != NO_VENDOR
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(DEVICE_ID)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(COMMAND)
+    }
+
+    /// Writes the command register.
+    ///
+    /// This function is unsafe because enabling decoding or bus mastering lets the device touch
+    /// memory and ports the caller must have set up for it.
+    pub unsafe fn set_command(&self, command: u16) {
+        // the status half of the register only has bits that clear when written with 1
+        self.bus
+            .write(self.device, self.num, COMMAND, u32::from(command))
+    }
+
+    pub fn status(&self) -> u16 {
+        self.read_u16(STATUS)
+    }
+
+    pub fn class(&self) -> u8 {
+        self.read_u8(CLASS)
+    }
+
+    pub fn subclass(&self) -> u8 {
+        self.read_u8(SUBCLASS)
+    }
+
+    pub fn prog_if(&self) -> u8 {
+        self.read_u8(PROG_IF)
+    }
+
+    pub fn header_type(&self) -> HeaderType {
+        match self.read_u8(HEADER_TYPE) & !MULTIFUNCTION {
+            0x00 => HeaderType::General,
+            0x01 => HeaderType::PciToPciBridge,
+            0x02 => HeaderType::CardBusBridge,
+            other => HeaderType::Unknown(other),
+        }
+    }
+
+    /// Whether the device has functions past the first, only meaningful on function 0.
+    pub fn is_multifunction(&self) -> bool {
+        self.read_u8(HEADER_TYPE) & MULTIFUNCTION != 0
+    }
+
+    /// The legacy PIC IRQ the firmware routed the function's interrupt pin to.
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_u8(INTERRUPT_LINE)
+    }
+
+    pub fn interrupt_pin(&self) -> Option<InterruptPin> {
+        match self.read_u8(INTERRUPT_PIN) {
+            1 => Some(InterruptPin::A),
+            2 => Some(InterruptPin::B),
+            3 => Some(InterruptPin::C),
+            4 => Some(InterruptPin::D),
+            _ => None,
+        }
+    }
+
+    fn read_u8(&self, offset: u16) -> u8 {
+        let register = unsafe { self.bus.read(self.device, self.num, offset & !0b11) };
+
+        (register >> ((offset & 0b11) * 8)) as u8
+    }
+
+    fn read_u16(&self, offset: u16) -> u16 {
+        let register = unsafe { self.bus.read(self.device, self.num, offset & !0b11) };
+
+        (register >> ((offset & 0b10) * 8)) as u16
+    }
+}
+
+impl fmt::Display for PciFunction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.bus.num,
+            self.device,
+            self.num,
+            self.vendor_id(),
+            self.device_id(),
+            self.class(),
+            self.subclass(),
+            self.prog_if(),
+        )
+    }
+}
+
+/// The layout of the configuration space past the common header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    General,
+    PciToPciBridge,
+    CardBusBridge,
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptPin {
+    A,
+    B,
+    C,
+    D,
+}
+
+pub struct PciBus<'pci> {
     pub pci: &'pci dyn ConfigAccess,
     pub num: u8,
 }
 
 impl<'pci> PciBus<'pci> {
+    pub fn new(pci: &'pci dyn ConfigAccess, num: u8) -> Self {
+        Self { pci, num }
+    }
+
     pub fn devices(&'pci self) -> PciBusIter<'pci> {
         PciBusIter::new(self)
     }
@@ -24,7 +205,7 @@ impl<'pci> PciBus<'pci> {
     }
 }
 
-struct PciBusIter<'pci> {
+pub struct PciBusIter<'pci> {
     bus: &'pci PciBus<'pci>,
     num: u8,
 }
@@ -52,6 +233,11 @@ impl<'pci> Iterator for PciBusIter<'pci> {
     }
 }
 
+/// Reads and writes the 32-bit registers of a function's configuration space. `offset` must be a
+/// multiple of 4.
+///
+/// The plain methods serialise accesses themselves, the `_nolock` ones leave it to the caller to
+/// make sure no other access can run at the same time.
 pub trait ConfigAccess {
     unsafe fn read_nolock(&self, bus: u8, dev: u8, func: u8, offset: u16) -> u32;
     unsafe fn read(&self, bus: u8, dev: u8, func: u8, offset: u16) -> u32;
@@ -60,35 +246,79 @@ pub trait ConfigAccess {
     unsafe fn write(&self, bus: u8, dev: u8, func: u8, offset: u16, value: u32);
 }
 
-// fn pci_config_read_word(bus: u8, slot: u8, func: u8, offset: u8) -> u16 {
-//     let bus = bus as u32;
-//     let slot = slot as u32;
-//     let func = func as u32;
+/// The legacy configuration mechanism through ports `0xcf8` and `0xcfc`, which only reaches the
+/// first 256 bytes of each function's configuration space.
+pub struct PortConfigAccess {
+    // an access is two port accesses that mustn't be interleaved with another's
+    lock: Mutex<()>,
+}
+
+impl PortConfigAccess {
+    pub const fn new() -> Self {
+        Self {
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl ConfigAccess for PortConfigAccess {
+    unsafe fn read_nolock(&self, bus: u8, dev: u8, func: u8, offset: u16) -> u32 {
+        Port::<u32>::new(CONFIG_ADDRESS).write(config_address(bus, dev, func, offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    }
+
+    // Interrupts are kept off while locked, so a handler reading configuration space can't
+    // deadlock with the code it interrupted
+    unsafe fn read(&self, bus: u8, dev: u8, func: u8, offset: u16) -> u32 {
+        interrupts::without_interrupts(|| {
+            let _guard = self.lock.lock();
+            self.read_nolock(bus, dev, func, offset)
+        })
+    }
 
-//     let address = bus << 16 | slot << 11 | func << 8 | (offset as u32 & 0xFC) | (0x90000000);
-//     unsafe { Port::new(0xCf8).write(address) };
+    unsafe fn write_nolock(&self, bus: u8, dev: u8, func: u8, offset: u16, value: u32) {
+        Port::<u32>::new(CONFIG_ADDRESS).write(config_address(bus, dev, func, offset));
+        Port::<u32>::new(CONFIG_DATA).write(value)
+    }
 
-//     return unsafe { (Port::<u32>::new(0xCFC).read() >> ((offset & 2) * 8) & 0xFFFF) as u16 };
-// }
+    unsafe fn write(&self, bus: u8, dev: u8, func: u8, offset: u16, value: u32) {
+        interrupts::without_interrupts(|| {
+            let _guard = self.lock.lock();
+            self.write_nolock(bus, dev, func, offset, value)
+        })
+    }
+}
 
-// fn pci_check_vendor( bus: u8,  slot: u8) -> u16 {
-//     uint16_t vendor, device;
-//     let vendor: u16;
-//     let device: u16;
+fn config_address(bus: u8, dev: u8, func: u8, offset: u16) -> u32 {
+    debug_assert!(dev < 32 && func < 8 && offset < 256 && offset % 4 == 0);
+    let (bus, dev, func) = (u32::from(bus), u32::from(dev), u32::from(func));
 
-//     /* Try and read the first configuration register. Since there are no
-//      * vendors that == 0xFFFF, it must be a non-existent device. */
-//     if ((vendor = pciConfigReadWord(bus, slot, 0, 0)) != 0xFFFF) {
-//        device = pciConfigReadWord(bus, slot, 0, 2);
-//        . . .
-//     } return (vendor);
-// }
+    1 << 31 | bus << 16 | dev << 11 | func << 8 | u32::from(offset)
+}
+
+fn check_device(device: &PciDevice, f: &mut impl FnMut(&PciFunction)) {
+    device.functions().for_each(|function| f(&function));
+}
 
-fn check_device(bus: u8, device: u8) {
-    let function = 0;
-    // let vendor_id = get_vendor_id();
+/// Calls `f` with every function on every bus, found by trying each possible device.
+pub fn check_all_buses(pci: &dyn ConfigAccess, mut f: impl FnMut(&PciFunction)) {
+    (0..=255).for_each(|bus| {
+        let bus = PciBus::new(pci, bus);
+        bus.devices()
+            .for_each(|device| check_device(&device, &mut f));
+    });
 }
 
-fn check_all_buses() {
-    (0..=255).for_each(|bus| (0..32).for_each(|device| check_device(bus, device)));
+#[test_case]
+fn host_bridge_is_at_the_first_slot() {
+    let bus = PciBus::new(&PCI_CONFIG, 0);
+    let host_bridge = bus.devices().next().unwrap().function(0);
+
+    assert!(host_bridge.is_present());
+    assert_eq!((host_bridge.class(), host_bridge.subclass()), (0x06, 0x00));
+    assert_eq!(host_bridge.header_type(), HeaderType::General);
+    assert_eq!(
+        bus.devices().nth(31).unwrap().function(7).vendor_id(),
+        NO_VENDOR
+    );
 }
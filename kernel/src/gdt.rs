@@ -1,7 +1,14 @@
-use crate::idt::DOUBLE_FAULT_IST_INDEX;
+use crate::{
+    idt::{
+        DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NON_MASKABLE_INTERRUPT_IST_INDEX,
+        PAGE_FAULT_IST_INDEX,
+    },
+    mem::{vmm, MemoryResult},
+};
+use core::ptr;
 use lazy_static::lazy_static;
 use x86_64::{
-    instructions::tables,
+    instructions::{interrupts, tables},
     registers::segmentation::{Segment, CS},
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
@@ -10,28 +17,35 @@ use x86_64::{
     VirtAddr,
 };
 
+const IST_STACK_SIZE: usize = 4096 * 5;
+const IST_STACK_COUNT: usize = 4;
+// Each stack's IST index and the name it's reported by when it overflows
+const IST_STACKS: [(u16, &str); IST_STACK_COUNT] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault"),
+    (NON_MASKABLE_INTERRUPT_IST_INDEX, "non-maskable interrupt"),
+    (MACHINE_CHECK_IST_INDEX, "machine check"),
+    (PAGE_FAULT_IST_INDEX, "page fault"),
+];
+
 lazy_static! {
     static ref GLOBAL_DESCRIPTOR_TABLE_DATA: GDTData = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tts_selector = gdt.add_entry(Descriptor::tss_segment(&TASK_STATE_SEGMENT));
+        // SAFETY: the TSS is only written to while setting up its stacks
+        let tss = unsafe { &*ptr::addr_of!(TASK_STATE_SEGMENT) };
+        let tts_selector = gdt.add_entry(Descriptor::tss_segment(tss));
 
         GDTData::new(gdt, Selectors::new(code_selector, tts_selector))
     };
-    static ref TASK_STATE_SEGMENT: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+}
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            
-            stack_start + STACK_SIZE
-        };
+// The CPU reads the interrupt stack table out of here on every interrupt that uses it, so the
+// stacks can be swapped after the TSS is loaded
+static mut TASK_STATE_SEGMENT: TaskStateSegment = TaskStateSegment::new();
 
-        tss
-    };
-}
+// Interrupt stacks for until the VMM is up, these have no guard pages
+static mut BOOT_STACKS: [[u8; IST_STACK_SIZE]; IST_STACK_COUNT] =
+    [[0; IST_STACK_SIZE]; IST_STACK_COUNT];
 
 struct GDTData {
     gdt: GlobalDescriptorTable,
@@ -56,6 +70,11 @@ impl Selectors {
 }
 
 pub fn initialize() {
+    IST_STACKS.iter().enumerate().for_each(|(i, &(index, _))| {
+        let stack = unsafe { ptr::addr_of!(BOOT_STACKS[i]) };
+        let top = VirtAddr::from_ptr(stack) + IST_STACK_SIZE;
+        unsafe { set_interrupt_stack(index, top) };
+    });
     GLOBAL_DESCRIPTOR_TABLE_DATA.gdt.load();
 
     let selectors = &GLOBAL_DESCRIPTOR_TABLE_DATA.selectors;
@@ -64,3 +83,22 @@ pub fn initialize() {
         tables::load_tss(selectors.tts)
     }
 }
+
+/// Moves each interrupt stack onto one from the VMM, with a guard page below it so an overflow
+/// faults instead of silently running into whatever sits below.
+///
+/// Must be called once, after `vmm::initialize` and outside of any interrupt handler.
+pub fn allocate_stacks() -> MemoryResult<()> {
+    IST_STACKS.iter().try_for_each(|&(index, name)| {
+        let top = vmm::map_stack(IST_STACK_SIZE as u64, name)?;
+        interrupts::without_interrupts(|| unsafe { set_interrupt_stack(index, top) });
+
+        Ok(())
+    })
+}
+
+// The caller must make sure no interrupt is running on the stack being replaced
+unsafe fn set_interrupt_stack(index: u16, top: VirtAddr) {
+    let tss = ptr::addr_of_mut!(TASK_STATE_SEGMENT);
+    (*tss).interrupt_stack_table[index as usize] = top;
+}
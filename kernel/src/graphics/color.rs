@@ -11,6 +11,20 @@ pub enum Color {
 }
 
 impl Color {
+    /// Mixes in `amount / 255` of `other`, giving an RGB color.
+    pub fn blend(&self, other: Color, amount: u8) -> Color {
+        let (from, to) = (self.as_rgb(), other.as_rgb());
+        let amount = amount as u32;
+        let channel = |shift: u32| {
+            let from = (from >> shift) as u8 as u32;
+            let to = (to >> shift) as u8 as u32;
+
+            ((from * (255 - amount) + to * amount) / 255) << shift
+        };
+
+        Self::Rgb(channel(24) | channel(16) | channel(8))
+    }
+
     pub fn as_rgb(&self) -> u32 {
         match *self {
             Self::White => 0xffffff00,
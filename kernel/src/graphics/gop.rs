@@ -44,6 +44,14 @@ impl<'a> GraphicsDevice for GopDevice<'a> {
     fn fill(&mut self, color: Color) {
         (0..self.height).for_each(|y| (0..self.width).for_each(|x| self.set_pixel(x, y, color)));
     }
+
+    fn scroll_up(&mut self, rows: usize, color: Color) {
+        let rows = rows.min(self.height);
+        // one copy of the whole framebuffer is far cheaper than redrawing it pixel by pixel
+        self.buffer
+            .copy_within(rows * self.pitch..self.height * self.pitch, 0);
+        self.fill_rect(0, self.height - rows, self.width, rows, color);
+    }
 }
 
 impl<'a> GopDevice<'a> {
@@ -15,4 +15,14 @@ pub trait GraphicsDevice {
     fn set_byte(&mut self, offset: usize, value: u8);
     fn set_pixel(&mut self, x: usize, y: usize, color: Color);
     fn fill(&mut self, color: Color);
+    /// Moves the whole screen up by `rows`, filling the rows uncovered at the bottom with `color`.
+    fn scroll_up(&mut self, rows: usize, color: Color);
+
+    /// Fills a rectangle, clipped to the edges of the screen.
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let x_end = (x + width).min(self.width());
+        let y_end = (y + height).min(self.height());
+
+        (y..y_end).for_each(|y| (x..x_end).for_each(|x| self.set_pixel(x, y, color)));
+    }
 }
@@ -1,46 +1,497 @@
+use crate::{
+    apic::{self, Apic},
+    mem::vmm::{self, Access},
+    pic::{ChainedPics, PIC_1_OFFSET, PIC_2_OFFSET},
+    println,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-
-use crate::halt_loop;
+use spin::{Mutex, RwLock};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr2,
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::{PageSize, Size4KiB},
+    },
+    VirtAddr,
+};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NON_MASKABLE_INTERRUPT_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+pub const IRQ_COUNT: usize = 16;
+
+/// A driver's handler for a hardware IRQ line. End of interrupt is sent by the dispatcher once it
+/// returns.
+pub type IrqHandler = fn();
+
+static PAGE_FAULT_PANICKED: AtomicBool = AtomicBool::new(false);
+static IRQ_HANDLERS: RwLock<[Option<IrqHandler>; IRQ_COUNT]> = RwLock::new([None; IRQ_COUNT]);
+
+/// The controller hardware IRQs arrive through. Drivers only deal in ISA IRQ numbers, both
+/// controllers deliver IRQ `n` at vector `PIC_1_OFFSET + n`.
+pub enum InterruptController {
+    Pic(ChainedPics),
+    Apic(Apic),
+}
+
+impl InterruptController {
+    fn mask(&mut self, irq: u8) {
+        match self {
+            Self::Pic(pics) => pics.mask(irq),
+            Self::Apic(apic) => apic.mask(irq),
+        }
+    }
+
+    fn unmask(&mut self, irq: u8) {
+        match self {
+            Self::Pic(pics) => pics.unmask(irq),
+            Self::Apic(apic) => apic.unmask(irq),
+        }
+    }
+
+    // The local APIC delivers its spurious interrupts on a dedicated vector that never reaches
+    // the dispatcher
+    fn is_spurious(&mut self, irq: u8) -> bool {
+        match self {
+            Self::Pic(pics) => pics.is_spurious(irq),
+            Self::Apic(_) => false,
+        }
+    }
+
+    fn end_of_interrupt(&mut self, irq: u8) {
+        match self {
+            Self::Pic(pics) => pics.end_of_interrupt(irq),
+            Self::Apic(apic) => apic.end_of_interrupt(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref INTERRUPT_CONTROLLER: Mutex<InterruptController> =
+        Mutex::new(InterruptController::Pic(unsafe {
+            ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+        }));
+}
 
 lazy_static! {
     static ref INTERRUPT_DESCRIPTOR_TABLE: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt.vmm_communication_exception.set_handler_fn(vmm_communication_exception_handler);
+        idt.security_exception.set_handler_fn(security_exception_handler);
+        IRQ_ENTRY_POINTS.iter().enumerate().for_each(|(irq, &entry_point)| {
+            idt[PIC_1_OFFSET as usize + irq].set_handler_fn(entry_point);
+        });
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious_handler);
 
-        // TODO: create test idt and use this for double fault handling
-        // unsafe {
-        //     idt.double_fault
-        //         .set_handler_fn(double_fault_handler)
-        //         .set_stack_index(DOUBLE_FAULT_IST_INDEX);
-        // }
+        // SAFETY: gdt::initialize points each of these IST entries at a dedicated stack
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(non_maskable_interrupt_handler)
+                .set_stack_index(NON_MASKABLE_INTERRUPT_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(MACHINE_CHECK_IST_INDEX);
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(PAGE_FAULT_IST_INDEX);
+        }
 
         idt
     };
 }
 
+/// Loads the IDT and remaps the legacy PICs, which deliver IRQs until `use_apic` is called.
 pub fn initialize() {
+    load();
+    if let InterruptController::Pic(pics) = &mut *INTERRUPT_CONTROLLER.lock() {
+        pics.initialize();
+    }
+}
+
+/// Whether the panic in progress came from the page fault handler. Another page fault would start
+/// over at the top of the stack the panic is running on, so the panic mustn't touch anything that
+/// could need backing, like the heap.
+pub fn page_fault_panicked() -> bool {
+    PAGE_FAULT_PANICKED.load(Ordering::Relaxed)
+}
+
+/// Loads the kernel's IDT, without touching the interrupt controllers.
+pub fn load() {
     INTERRUPT_DESCRIPTOR_TABLE.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
-    unimplemented!("should print stack frame");
+/// Hands IRQ delivery over to the APIC, carrying over every IRQ that currently has a handler.
+pub fn use_apic(mut apic: Apic) {
+    interrupts::without_interrupts(|| {
+        let handlers = IRQ_HANDLERS.read();
+        (0..IRQ_COUNT as u8)
+            .filter(|&irq| handlers[irq as usize].is_some())
+            .for_each(|irq| apic.unmask(irq));
+
+        let mut controller = INTERRUPT_CONTROLLER.lock();
+        if let InterruptController::Pic(pics) = &mut *controller {
+            pics.disable();
+        }
+        *controller = InterruptController::Apic(apic);
+    });
+}
+
+/// Installs `handler` for a hardware IRQ line and unmasks it.
+pub fn register_irq(irq: u8, handler: IrqHandler) {
+    interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.write()[irq as usize] = Some(handler);
+        INTERRUPT_CONTROLLER.lock().unmask(irq);
+    });
+}
+
+/// Masks a hardware IRQ line and removes its handler.
+pub fn unregister_irq(irq: u8) {
+    interrupts::without_interrupts(|| {
+        INTERRUPT_CONTROLLER.lock().mask(irq);
+        IRQ_HANDLERS.write()[irq as usize] = None;
+    });
+}
+
+fn dispatch_irq(irq: u8) {
+    if INTERRUPT_CONTROLLER.lock().is_spurious(irq) {
+        return;
+    }
+
+    let handler = IRQ_HANDLERS.read()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+
+    INTERRUPT_CONTROLLER.lock().end_of_interrupt(irq);
+}
+
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {}
+
+// The CPU doesn't tell a handler which vector it was invoked through, so each IRQ gets its own
+// entry point that forwards its number to the dispatcher
+macro_rules! irq_entry_points {
+    ($($name:ident = $irq:literal),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq)
+            }
+        )*
+
+        const IRQ_ENTRY_POINTS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] =
+            [$($name),*];
+    };
+}
+
+irq_entry_points!(
+    irq0_handler = 0,
+    irq1_handler = 1,
+    irq2_handler = 2,
+    irq3_handler = 3,
+    irq4_handler = 4,
+    irq5_handler = 5,
+    irq6_handler = 6,
+    irq7_handler = 7,
+    irq8_handler = 8,
+    irq9_handler = 9,
+    irq10_handler = 10,
+    irq11_handler = 11,
+    irq12_handler = 12,
+    irq13_handler = 13,
+    irq14_handler = 14,
+    irq15_handler = 15,
+);
+
+// Reports an exception we can return from
+fn report(name: &str, stack_frame: &InterruptStackFrame) {
+    println!("EXCEPTION: {name}\n{stack_frame:#?}");
+}
+
+fn fatal(name: &str, stack_frame: &InterruptStackFrame) -> ! {
+    panic!("EXCEPTION: {name}\n{stack_frame:#?}")
+}
+
+fn fatal_with_error_code(name: &str, stack_frame: &InterruptStackFrame, error_code: u64) -> ! {
+    panic!("EXCEPTION: {name} (error code {error_code:#x})\n{stack_frame:#?}")
+}
+
+fn fatal_with_selector(name: &str, stack_frame: &InterruptStackFrame, error_code: u64) -> ! {
+    panic!(
+        "EXCEPTION: {name} ({})\n{stack_frame:#?}",
+        SelectorErrorCode(error_code)
+    )
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    fatal("DIVIDE ERROR", &stack_frame)
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    report("DEBUG", &stack_frame);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    report("NON-MASKABLE INTERRUPT", &stack_frame);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    report("BREAKPOINT", &stack_frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    report("OVERFLOW", &stack_frame);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    fatal("BOUND RANGE EXCEEDED", &stack_frame)
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    fatal("INVALID OPCODE", &stack_frame)
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    fatal("DEVICE NOT AVAILABLE", &stack_frame)
 }
 
 extern "x86-interrupt" fn double_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
 ) -> ! {
-    unimplemented!("should print stack frame and panic");
+    // a page fault that couldn't be delivered leaves its address behind in CR2, but so does one
+    // that was handled long ago, so it's only trusted if the stack pointer was right next to it
+    let address = VirtAddr::new_truncate(Cr2::read_raw());
+    let distance = stack_frame
+        .stack_pointer
+        .as_u64()
+        .abs_diff(address.as_u64());
+    if let Some(name) = vmm::overflowed_stack(address).filter(|_| distance <= Size4KiB::SIZE) {
+        panic!("EXCEPTION: DOUBLE FAULT (overflowed the {name} stack)\n{stack_frame:#?}")
+    }
+
+    fatal_with_error_code("DOUBLE FAULT", &stack_frame, error_code)
 }
 
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fatal_with_selector("INVALID TSS", &stack_frame, error_code)
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_with_selector("SEGMENT NOT PRESENT", &stack_frame, error_code)
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_with_selector("STACK SEGMENT FAULT", &stack_frame, error_code)
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_with_selector("GENERAL PROTECTION FAULT", &stack_frame, error_code)
+}
+
+// Runs on its own stack and mustn't fault itself, so it leaves the locks it can't get alone
 extern "x86-interrupt" fn page_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let address = VirtAddr::new_truncate(Cr2::read_raw());
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Access::Execute
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        Access::Write
+    } else {
+        Access::Read
+    };
+
+    if let Err(invalid) = vmm::handle_fault(address, access) {
+        PAGE_FAULT_PANICKED.store(true, Ordering::Relaxed);
+        panic!(
+            "EXCEPTION: PAGE FAULT\n{invalid}\ncause: {} ({error_code:?})\n{stack_frame:#?}",
+            PageFaultDescription(error_code),
+        )
+    }
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fatal("x87 FLOATING POINT", &stack_frame)
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_with_error_code("ALIGNMENT CHECK", &stack_frame, error_code)
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    fatal("MACHINE CHECK", &stack_frame)
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fatal("SIMD FLOATING POINT", &stack_frame)
+}
+
+extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+    fatal("VIRTUALIZATION", &stack_frame)
+}
+
+extern "x86-interrupt" fn vmm_communication_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_with_error_code("VMM COMMUNICATION", &stack_frame, error_code)
+}
+
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
 ) {
-    halt_loop()
+    fatal_with_error_code("SECURITY", &stack_frame, error_code)
+}
+
+/// Decodes the error code pushed by faults that reference a segment selector.
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "error code 0x0");
+        }
+
+        let table = match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        let index = (self.0 >> 3) & 0x1fff;
+        let external = if self.0 & 1 != 0 { ", external" } else { "" };
+
+        write!(
+            f,
+            "error code {:#x}: {table} index {index}{external}",
+            self.0
+        )
+    }
+}
+
+/// Describes a page fault error code as a sentence, eg. "kernel write to a non-present page".
+struct PageFaultDescription(PageFaultErrorCode);
+
+impl fmt::Display for PageFaultDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.0.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        let access = if self.0.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if self.0.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if self.0.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a protected page"
+        } else {
+            "a non-present page"
+        };
+
+        write!(f, "{mode} {access} {page}")?;
+        if self.0.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, " (reserved bit set in page table)")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+lazy_static! {
+    // Leaves out the page fault handler, so a fault on a guard page can't be handled and escalates
+    // to a double fault
+    static ref TEST_INTERRUPT_DESCRIPTOR_TABLE: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+// Getting here is what the test wanted, there's nothing to return to so we jump back to the runner
+#[cfg(test)]
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    crate::testing::leave_test(crate::testing::Outcome::Returned)
+}
+
+#[test_case]
+fn stack_overflow_double_faults() {
+    #[allow(unconditional_recursion)]
+    fn overflow() {
+        overflow();
+        // keeps the recursive call from being turned into a loop
+        core::hint::black_box(0);
+    }
+
+    // an IRQ would find no handler in the test IDT and double fault too, passing the test early
+    interrupts::disable();
+    TEST_INTERRUPT_DESCRIPTOR_TABLE.load();
+    overflow();
+}
+
+#[cfg(test)]
+crate::should_panic! {
+    fn stack_overflow_is_caught_by_the_guard_page() {
+        #[allow(unconditional_recursion)]
+        fn overflow() {
+            overflow();
+            core::hint::black_box(0);
+        }
+
+        overflow();
+    }
+}
+
+#[test_case]
+fn breakpoint_returns() {
+    x86_64::instructions::interrupts::int3();
 }
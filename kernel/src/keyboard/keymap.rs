@@ -0,0 +1,243 @@
+use super::{KeyCode, Modifiers};
+
+/// Maps physical keys to the characters they produce under a keyboard layout.
+pub trait Keymap: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn map(&self, key: KeyCode, modifiers: Modifiers) -> Option<char>;
+}
+
+pub struct Us;
+pub struct Uk;
+pub struct De;
+
+pub static US: Us = Us;
+pub static UK: Uk = Uk;
+pub static DE: De = De;
+
+impl Keymap for Us {
+    fn name(&self) -> &'static str {
+        "us"
+    }
+
+    fn map(&self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        common(key, modifiers).or_else(|| us(key, modifiers.shift))
+    }
+}
+
+impl Keymap for Uk {
+    fn name(&self) -> &'static str {
+        "uk"
+    }
+
+    fn map(&self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        use KeyCode::*;
+
+        if let Some(char) = common(key, modifiers) {
+            return Some(char);
+        }
+        if modifiers.alt_gr {
+            return match key {
+                Key4 => Some('€'),
+                _ => None,
+            };
+        }
+
+        let shift = modifiers.shift;
+        let char = match key {
+            Backtick => pick(shift, '`', '¬'),
+            Key2 => pick(shift, '2', '"'),
+            Key3 => pick(shift, '3', '£'),
+            Quote => pick(shift, '\'', '@'),
+            Backslash => pick(shift, '#', '~'),
+            NonUsBackslash => pick(shift, '\\', '|'),
+            _ => return us(key, shift),
+        };
+
+        Some(char)
+    }
+}
+
+impl Keymap for De {
+    fn name(&self) -> &'static str {
+        "de"
+    }
+
+    fn map(&self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        use KeyCode::*;
+
+        if modifiers.alt_gr {
+            return match key {
+                Q => Some('@'),
+                E => Some('€'),
+                M => Some('µ'),
+                Key2 => Some('²'),
+                Key3 => Some('³'),
+                Key7 => Some('{'),
+                Key8 => Some('['),
+                Key9 => Some(']'),
+                Key0 => Some('}'),
+                Minus => Some('\\'),
+                RightBracket => Some('~'),
+                NonUsBackslash => Some('|'),
+                _ => None,
+            };
+        }
+
+        // QWERTZ swaps Y and Z, everything else that's a letter on both layouts stays put
+        let key = match key {
+            Y => Z,
+            Z => Y,
+            key => key,
+        };
+        if let Some(char) = common(key, modifiers) {
+            return Some(char);
+        }
+
+        let shift = modifiers.shift;
+        let letter = |lower: char, upper: char| pick(shift ^ modifiers.caps_lock, lower, upper);
+        let char = match key {
+            Backtick => pick(shift, '^', '°'),
+            Key1 => pick(shift, '1', '!'),
+            Key2 => pick(shift, '2', '"'),
+            Key3 => pick(shift, '3', '§'),
+            Key4 => pick(shift, '4', '$'),
+            Key5 => pick(shift, '5', '%'),
+            Key6 => pick(shift, '6', '&'),
+            Key7 => pick(shift, '7', '/'),
+            Key8 => pick(shift, '8', '('),
+            Key9 => pick(shift, '9', ')'),
+            Key0 => pick(shift, '0', '='),
+            Minus => pick(shift, 'ß', '?'),
+            Equals => pick(shift, '´', '`'),
+            LeftBracket => letter('ü', 'Ü'),
+            RightBracket => pick(shift, '+', '*'),
+            Semicolon => letter('ö', 'Ö'),
+            Quote => letter('ä', 'Ä'),
+            Backslash => pick(shift, '#', '\''),
+            NonUsBackslash => pick(shift, '<', '>'),
+            Comma => pick(shift, ',', ';'),
+            Period => pick(shift, '.', ':'),
+            Slash => pick(shift, '-', '_'),
+            _ => return None,
+        };
+
+        Some(char)
+    }
+}
+
+fn pick(shift: bool, normal: char, shifted: char) -> char {
+    match shift {
+        false => normal,
+        true => shifted,
+    }
+}
+
+// Letters, whitespace and the numpad, which come out the same on every layout here
+fn common(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    use KeyCode::*;
+
+    let letter = match key {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => {
+            let char = match key {
+                Space => ' ',
+                Tab => '\t',
+                Enter | NumpadEnter => '\n',
+                NumpadDivide => '/',
+                NumpadMultiply => '*',
+                NumpadSubtract => '-',
+                NumpadAdd => '+',
+                _ if !modifiers.num_lock => return None,
+                Numpad0 => '0',
+                Numpad1 => '1',
+                Numpad2 => '2',
+                Numpad3 => '3',
+                Numpad4 => '4',
+                Numpad5 => '5',
+                Numpad6 => '6',
+                Numpad7 => '7',
+                Numpad8 => '8',
+                Numpad9 => '9',
+                NumpadPeriod => '.',
+                _ => return None,
+            };
+
+            return Some(char);
+        }
+    };
+
+    match modifiers.shift ^ modifiers.caps_lock {
+        false => Some(letter),
+        true => Some(letter.to_ascii_uppercase()),
+    }
+}
+
+fn us(key: KeyCode, shift: bool) -> Option<char> {
+    use KeyCode::*;
+
+    let char = match key {
+        Backtick => pick(shift, '`', '~'),
+        Key1 => pick(shift, '1', '!'),
+        Key2 => pick(shift, '2', '@'),
+        Key3 => pick(shift, '3', '#'),
+        Key4 => pick(shift, '4', '$'),
+        Key5 => pick(shift, '5', '%'),
+        Key6 => pick(shift, '6', '^'),
+        Key7 => pick(shift, '7', '&'),
+        Key8 => pick(shift, '8', '*'),
+        Key9 => pick(shift, '9', '('),
+        Key0 => pick(shift, '0', ')'),
+        Minus => pick(shift, '-', '_'),
+        Equals => pick(shift, '=', '+'),
+        LeftBracket => pick(shift, '[', '{'),
+        RightBracket => pick(shift, ']', '}'),
+        Backslash => pick(shift, '\\', '|'),
+        Semicolon => pick(shift, ';', ':'),
+        Quote => pick(shift, '\'', '"'),
+        Comma => pick(shift, ',', '<'),
+        Period => pick(shift, '.', '>'),
+        Slash => pick(shift, '/', '?'),
+        NonUsBackslash => pick(shift, '\\', '|'),
+        _ => return None,
+    };
+
+    Some(char)
+}
+
+#[test_case]
+fn layouts_differ_where_expected() {
+    let shifted = Modifiers {
+        shift: true,
+        ..Modifiers::default()
+    };
+
+    assert_eq!(US.map(KeyCode::Key2, shifted), Some('@'));
+    assert_eq!(UK.map(KeyCode::Key2, shifted), Some('"'));
+    assert_eq!(DE.map(KeyCode::Y, Modifiers::default()), Some('z'));
+    assert_eq!(DE.map(KeyCode::Semicolon, shifted), Some('Ö'));
+}
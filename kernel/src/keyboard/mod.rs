@@ -0,0 +1,261 @@
+pub mod keymap;
+mod scancode;
+
+pub use keymap::Keymap;
+pub use scancode::ScancodeSet;
+
+use crate::{idt, queue::RingBuffer};
+use scancode::Decoder;
+use spin::{Mutex, RwLock};
+use x86_64::instructions::{interrupts, port::Port};
+
+const KEYBOARD_IRQ: u8 = 1;
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const EVENT_CAPACITY: usize = 128;
+
+static EVENTS: RingBuffer<KeyEvent, EVENT_CAPACITY> = RingBuffer::new();
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new(ScancodeSet::Set1));
+static KEYMAP: RwLock<&'static dyn Keymap> = RwLock::new(&keymap::US);
+
+/// Drains anything the controller buffered before we were listening and starts handling IRQ 1.
+pub fn initialize() {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    unsafe {
+        while status.read() & 0x01 != 0 {
+            data.read();
+        }
+    }
+
+    idt::register_irq(KEYBOARD_IRQ, interrupt_handler);
+}
+
+/// Takes the oldest key event, if any are waiting.
+pub fn next_event() -> Option<KeyEvent> {
+    EVENTS.pop()
+}
+
+pub fn has_events() -> bool {
+    !EVENTS.is_empty()
+}
+
+pub fn set_keymap(keymap: &'static dyn Keymap) {
+    interrupts::without_interrupts(|| *KEYMAP.write() = keymap);
+}
+
+pub fn set_scancode_set(set: ScancodeSet) {
+    interrupts::without_interrupts(|| KEYBOARD.lock().decoder.set_set(set));
+}
+
+fn interrupt_handler() {
+    let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+    if let Some(event) = KEYBOARD.lock().feed(byte, *KEYMAP.read()) {
+        // there's nobody to report a full queue to, the key is dropped like on a real terminal
+        let _ = EVENTS.push(event);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub control: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+    /// The character the key produces under the active keymap, only set for presses.
+    pub character: Option<char>,
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    modifiers: Modifiers,
+    left_shift: bool,
+    right_shift: bool,
+    left_control: bool,
+    right_control: bool,
+}
+
+impl Keyboard {
+    const fn new(set: ScancodeSet) -> Self {
+        Self {
+            decoder: Decoder::new(set),
+            modifiers: Modifiers {
+                shift: false,
+                control: false,
+                alt: false,
+                alt_gr: false,
+                caps_lock: false,
+                num_lock: false,
+                scroll_lock: false,
+            },
+            left_shift: false,
+            right_shift: false,
+            left_control: false,
+            right_control: false,
+        }
+    }
+
+    fn feed(&mut self, byte: u8, keymap: &dyn Keymap) -> Option<KeyEvent> {
+        let (code, state) = self.decoder.feed(byte)?;
+        let pressed = state == KeyState::Pressed;
+        match code {
+            KeyCode::LeftShift => self.left_shift = pressed,
+            KeyCode::RightShift => self.right_shift = pressed,
+            KeyCode::LeftControl => self.left_control = pressed,
+            KeyCode::RightControl => self.right_control = pressed,
+            KeyCode::LeftAlt => self.modifiers.alt = pressed,
+            KeyCode::RightAlt => self.modifiers.alt_gr = pressed,
+            KeyCode::CapsLock if pressed => self.modifiers.caps_lock ^= true,
+            KeyCode::NumLock if pressed => self.modifiers.num_lock ^= true,
+            KeyCode::ScrollLock if pressed => self.modifiers.scroll_lock ^= true,
+            _ => {}
+        }
+        self.modifiers.shift = self.left_shift || self.right_shift;
+        self.modifiers.control = self.left_control || self.right_control;
+
+        let character = match pressed {
+            true => keymap.map(code, self.modifiers),
+            false => None,
+        };
+
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifiers,
+            character,
+        })
+    }
+}
+
+/// A physical key, named after its position on a US layout keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+
+    LeftShift,
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+
+    LeftControl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Menu,
+    RightControl,
+
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    ArrowUp,
+    ArrowLeft,
+    ArrowDown,
+    ArrowRight,
+
+    NumLock,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadSubtract,
+    NumpadAdd,
+    NumpadEnter,
+    NumpadPeriod,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+}
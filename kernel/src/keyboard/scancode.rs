@@ -0,0 +1,343 @@
+use super::{KeyCode, KeyState};
+
+const EXTENDED: u8 = 0xe0;
+const PAUSE: u8 = 0xe1;
+const SET_2_BREAK: u8 = 0xf0;
+
+/// The scancode set the keyboard controller delivers. Controllers translate to set 1 by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// Turns a stream of scancode bytes into key presses and releases.
+pub struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    released: bool,
+    // bytes left to swallow of the pause key's sequence, which has no break code
+    pause_remaining: u8,
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            extended: false,
+            released: false,
+            pause_remaining: 0,
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    pub fn set_set(&mut self, set: ScancodeSet) {
+        *self = Self::new(set);
+    }
+
+    /// Feeds a single byte to the decoder, returning a key once a full scancode has been seen.
+    pub fn feed(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        if self.pause_remaining > 0 {
+            self.pause_remaining -= 1;
+            return match self.pause_remaining {
+                0 => Some((KeyCode::Pause, KeyState::Pressed)),
+                _ => None,
+            };
+        }
+
+        match (self.set, byte) {
+            (_, EXTENDED) => {
+                self.extended = true;
+                return None;
+            }
+            (ScancodeSet::Set1, PAUSE) => {
+                self.pause_remaining = 5;
+                return None;
+            }
+            (ScancodeSet::Set2, PAUSE) => {
+                self.pause_remaining = 7;
+                return None;
+            }
+            (ScancodeSet::Set2, SET_2_BREAK) => {
+                self.released = true;
+                return None;
+            }
+            _ => {}
+        }
+
+        let extended = core::mem::take(&mut self.extended);
+        let (code, state) = match self.set {
+            ScancodeSet::Set1 => {
+                let state = match byte & 0x80 {
+                    0 => KeyState::Pressed,
+                    _ => KeyState::Released,
+                };
+                (set_1_key(byte & 0x7f, extended), state)
+            }
+            ScancodeSet::Set2 => {
+                let state = match core::mem::take(&mut self.released) {
+                    false => KeyState::Pressed,
+                    true => KeyState::Released,
+                };
+                (set_2_key(byte, extended), state)
+            }
+        };
+
+        code.map(|code| (code, state))
+    }
+}
+
+fn set_1_key(code: u8, extended: bool) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    let key = match (extended, code) {
+        (false, 0x01) => Escape,
+        (false, 0x02) => Key1,
+        (false, 0x03) => Key2,
+        (false, 0x04) => Key3,
+        (false, 0x05) => Key4,
+        (false, 0x06) => Key5,
+        (false, 0x07) => Key6,
+        (false, 0x08) => Key7,
+        (false, 0x09) => Key8,
+        (false, 0x0a) => Key9,
+        (false, 0x0b) => Key0,
+        (false, 0x0c) => Minus,
+        (false, 0x0d) => Equals,
+        (false, 0x0e) => Backspace,
+        (false, 0x0f) => Tab,
+        (false, 0x10) => Q,
+        (false, 0x11) => W,
+        (false, 0x12) => E,
+        (false, 0x13) => R,
+        (false, 0x14) => T,
+        (false, 0x15) => Y,
+        (false, 0x16) => U,
+        (false, 0x17) => I,
+        (false, 0x18) => O,
+        (false, 0x19) => P,
+        (false, 0x1a) => LeftBracket,
+        (false, 0x1b) => RightBracket,
+        (false, 0x1c) => Enter,
+        (false, 0x1d) => LeftControl,
+        (false, 0x1e) => A,
+        (false, 0x1f) => S,
+        (false, 0x20) => D,
+        (false, 0x21) => F,
+        (false, 0x22) => G,
+        (false, 0x23) => H,
+        (false, 0x24) => J,
+        (false, 0x25) => K,
+        (false, 0x26) => L,
+        (false, 0x27) => Semicolon,
+        (false, 0x28) => Quote,
+        (false, 0x29) => Backtick,
+        (false, 0x2a) => LeftShift,
+        (false, 0x2b) => Backslash,
+        (false, 0x2c) => Z,
+        (false, 0x2d) => X,
+        (false, 0x2e) => C,
+        (false, 0x2f) => V,
+        (false, 0x30) => B,
+        (false, 0x31) => N,
+        (false, 0x32) => M,
+        (false, 0x33) => Comma,
+        (false, 0x34) => Period,
+        (false, 0x35) => Slash,
+        (false, 0x36) => RightShift,
+        (false, 0x37) => NumpadMultiply,
+        (false, 0x38) => LeftAlt,
+        (false, 0x39) => Space,
+        (false, 0x3a) => CapsLock,
+        (false, 0x3b) => F1,
+        (false, 0x3c) => F2,
+        (false, 0x3d) => F3,
+        (false, 0x3e) => F4,
+        (false, 0x3f) => F5,
+        (false, 0x40) => F6,
+        (false, 0x41) => F7,
+        (false, 0x42) => F8,
+        (false, 0x43) => F9,
+        (false, 0x44) => F10,
+        (false, 0x45) => NumLock,
+        (false, 0x46) => ScrollLock,
+        (false, 0x47) => Numpad7,
+        (false, 0x48) => Numpad8,
+        (false, 0x49) => Numpad9,
+        (false, 0x4a) => NumpadSubtract,
+        (false, 0x4b) => Numpad4,
+        (false, 0x4c) => Numpad5,
+        (false, 0x4d) => Numpad6,
+        (false, 0x4e) => NumpadAdd,
+        (false, 0x4f) => Numpad1,
+        (false, 0x50) => Numpad2,
+        (false, 0x51) => Numpad3,
+        (false, 0x52) => Numpad0,
+        (false, 0x53) => NumpadPeriod,
+        (false, 0x56) => NonUsBackslash,
+        (false, 0x57) => F11,
+        (false, 0x58) => F12,
+        (true, 0x1c) => NumpadEnter,
+        (true, 0x1d) => RightControl,
+        (true, 0x35) => NumpadDivide,
+        (true, 0x37) => PrintScreen,
+        (true, 0x38) => RightAlt,
+        (true, 0x47) => Home,
+        (true, 0x48) => ArrowUp,
+        (true, 0x49) => PageUp,
+        (true, 0x4b) => ArrowLeft,
+        (true, 0x4d) => ArrowRight,
+        (true, 0x4f) => End,
+        (true, 0x50) => ArrowDown,
+        (true, 0x51) => PageDown,
+        (true, 0x52) => Insert,
+        (true, 0x53) => Delete,
+        (true, 0x5b) => LeftGui,
+        (true, 0x5c) => RightGui,
+        (true, 0x5d) => Menu,
+        // includes the fake shifts sent around extended keys
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+fn set_2_key(code: u8, extended: bool) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    let key = match (extended, code) {
+        (false, 0x01) => F9,
+        (false, 0x03) => F5,
+        (false, 0x04) => F3,
+        (false, 0x05) => F1,
+        (false, 0x06) => F2,
+        (false, 0x07) => F12,
+        (false, 0x09) => F10,
+        (false, 0x0a) => F8,
+        (false, 0x0b) => F6,
+        (false, 0x0c) => F4,
+        (false, 0x0d) => Tab,
+        (false, 0x0e) => Backtick,
+        (false, 0x11) => LeftAlt,
+        (false, 0x12) => LeftShift,
+        (false, 0x14) => LeftControl,
+        (false, 0x15) => Q,
+        (false, 0x16) => Key1,
+        (false, 0x1a) => Z,
+        (false, 0x1b) => S,
+        (false, 0x1c) => A,
+        (false, 0x1d) => W,
+        (false, 0x1e) => Key2,
+        (false, 0x21) => C,
+        (false, 0x22) => X,
+        (false, 0x23) => D,
+        (false, 0x24) => E,
+        (false, 0x25) => Key4,
+        (false, 0x26) => Key3,
+        (false, 0x29) => Space,
+        (false, 0x2a) => V,
+        (false, 0x2b) => F,
+        (false, 0x2c) => T,
+        (false, 0x2d) => R,
+        (false, 0x2e) => Key5,
+        (false, 0x31) => N,
+        (false, 0x32) => B,
+        (false, 0x33) => H,
+        (false, 0x34) => G,
+        (false, 0x35) => Y,
+        (false, 0x36) => Key6,
+        (false, 0x3a) => M,
+        (false, 0x3b) => J,
+        (false, 0x3c) => U,
+        (false, 0x3d) => Key7,
+        (false, 0x3e) => Key8,
+        (false, 0x41) => Comma,
+        (false, 0x42) => K,
+        (false, 0x43) => I,
+        (false, 0x44) => O,
+        (false, 0x45) => Key0,
+        (false, 0x46) => Key9,
+        (false, 0x49) => Period,
+        (false, 0x4a) => Slash,
+        (false, 0x4b) => L,
+        (false, 0x4c) => Semicolon,
+        (false, 0x4d) => P,
+        (false, 0x4e) => Minus,
+        (false, 0x52) => Quote,
+        (false, 0x54) => LeftBracket,
+        (false, 0x55) => Equals,
+        (false, 0x58) => CapsLock,
+        (false, 0x59) => RightShift,
+        (false, 0x5a) => Enter,
+        (false, 0x5b) => RightBracket,
+        (false, 0x5d) => Backslash,
+        (false, 0x61) => NonUsBackslash,
+        (false, 0x66) => Backspace,
+        (false, 0x69) => Numpad1,
+        (false, 0x6b) => Numpad4,
+        (false, 0x6c) => Numpad7,
+        (false, 0x70) => Numpad0,
+        (false, 0x71) => NumpadPeriod,
+        (false, 0x72) => Numpad2,
+        (false, 0x73) => Numpad5,
+        (false, 0x74) => Numpad6,
+        (false, 0x75) => Numpad8,
+        (false, 0x76) => Escape,
+        (false, 0x77) => NumLock,
+        (false, 0x78) => F11,
+        (false, 0x79) => NumpadAdd,
+        (false, 0x7a) => Numpad3,
+        (false, 0x7b) => NumpadSubtract,
+        (false, 0x7c) => NumpadMultiply,
+        (false, 0x7d) => Numpad9,
+        (false, 0x7e) => ScrollLock,
+        (false, 0x83) => F7,
+        (true, 0x11) => RightAlt,
+        (true, 0x14) => RightControl,
+        (true, 0x1f) => LeftGui,
+        (true, 0x27) => RightGui,
+        (true, 0x2f) => Menu,
+        (true, 0x4a) => NumpadDivide,
+        (true, 0x5a) => NumpadEnter,
+        (true, 0x69) => End,
+        (true, 0x6b) => ArrowLeft,
+        (true, 0x6c) => Home,
+        (true, 0x70) => Insert,
+        (true, 0x71) => Delete,
+        (true, 0x72) => ArrowDown,
+        (true, 0x74) => ArrowRight,
+        (true, 0x75) => ArrowUp,
+        (true, 0x7a) => PageDown,
+        (true, 0x7c) => PrintScreen,
+        (true, 0x7d) => PageUp,
+        // includes the fake shifts sent around extended keys
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+#[test_case]
+fn decodes_set_1_extended_release() {
+    let mut decoder = Decoder::new(ScancodeSet::Set1);
+    assert_eq!(decoder.feed(0xe0), None);
+    assert_eq!(
+        decoder.feed(0xc8),
+        Some((KeyCode::ArrowUp, KeyState::Released))
+    );
+}
+
+#[test_case]
+fn decodes_set_2_extended_release() {
+    let mut decoder = Decoder::new(ScancodeSet::Set2);
+    assert_eq!(decoder.feed(0xe0), None);
+    assert_eq!(decoder.feed(0xf0), None);
+    assert_eq!(
+        decoder.feed(0x75),
+        Some((KeyCode::ArrowUp, KeyState::Released))
+    );
+    assert_eq!(decoder.feed(0x1c), Some((KeyCode::A, KeyState::Pressed)));
+}
@@ -1,29 +1,48 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt, custom_test_frameworks, error_in_core)]
+#![feature(
+    abi_x86_interrupt,
+    alloc_error_handler,
+    custom_test_frameworks,
+    error_in_core
+)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_run"]
 
+mod acpi;
+mod apic;
+mod console;
+mod cpu;
 mod device;
 mod gdt;
 mod graphics;
 mod idt;
+mod keyboard;
 mod mem;
+mod pic;
+mod queue;
+mod ramdisk;
+mod serial;
 mod terminal;
+#[cfg(test)]
+mod testing;
+mod timer;
 
 #[macro_use]
 extern crate alloc;
 
 use alloc::rc::Rc;
 use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
-use core::{cell::RefCell, panic::PanicInfo};
+use core::{arch::asm, cell::RefCell, panic::PanicInfo};
 use graphics::GopDevice;
-use mem::{alloc::BootInfoFrameAllocator, heap, MemoryResult};
-use x86_64::instructions;
+use mem::{heap, MemoryResult};
+use x86_64::{instructions, PhysAddr, VirtAddr};
+
+const KERNEL_STACK_SIZE: u64 = 128 * 1024;
 
+// The bootloader's stack is only used until memory is set up, see `main`
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
-    // config.kernel_stack_size = 1024 * 1000 * 1000;
     config.mappings.physical_memory = Some(Mapping::Dynamic);
 
     config
@@ -32,48 +51,160 @@ pub static BOOTLOADER_CONFIG: BootloaderConfig = {
 entry_point!(main, config = &BOOTLOADER_CONFIG);
 
 fn main(boot_info: &'static mut BootInfo) -> ! {
-    #[cfg(test)]
-    test_run();
-
     // TODO: handle errors
     initialize_hardware(boot_info).unwrap();
 
+    // the rest runs on a stack with a guard page we know the name of
+    let stack_top = mem::vmm::map_stack(KERNEL_STACK_SIZE, "kernel").unwrap();
+    unsafe { switch_stack(stack_top, kernel_main) }
+}
+
+fn kernel_main() -> ! {
+    // The test runner exits QEMU once it's done
+    #[cfg(test)]
+    test_run();
+
     println!("/home/xiuxiu/documents > ls");
     println!("test.txt");
     println!("hello-world.txt");
+    terminal::set_prompt("> ");
 
-    halt_loop()
+    event_loop()
 }
 
 fn initialize_hardware(boot_info: &'static mut BootInfo) -> MemoryResult<()> {
     gdt::initialize();
     idt::initialize();
-    let mut memory_mapper = unsafe { mem::initialize(boot_info.physical_memory_offset.as_ref())? };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_regions) };
-    heap::initialize(&mut memory_mapper, &mut frame_allocator)?;
+    let physical_memory_offset = boot_info.physical_memory_offset.as_ref();
+    unsafe { mem::initialize(physical_memory_offset, &boot_info.memory_regions)? };
+    let kernel_address = PhysAddr::new(boot_info.kernel_addr);
+    unsafe { mem::image::protect(kernel_address, boot_info.kernel_image_offset)? };
+    heap::initialize()?;
+    mem::vmm::initialize()?;
+    gdt::allocate_stacks()?;
+    let ramdisk_address = boot_info.ramdisk_addr.as_ref().copied();
+    unsafe { ramdisk::initialize(ramdisk_address, boot_info.ramdisk_len) };
     let gop_device = Rc::new(RefCell::new(
         GopDevice::new(boot_info.framebuffer.as_mut()).unwrap(),
     ));
     crate::terminal::initialize(gop_device);
 
+    // The legacy PICs keep delivering IRQs if the firmware doesn't describe any APICs
+    if let Some(&rsdp_address) = boot_info.rsdp_addr.as_ref() {
+        let rsdp_address = PhysAddr::new(rsdp_address);
+        match unsafe { apic::initialize(rsdp_address) } {
+            Ok(apic) => idt::use_apic(apic),
+            Err(error) => println!("APIC unavailable, using the legacy PIC: {error}"),
+        }
+    }
+    timer::initialize();
+    keyboard::initialize();
+    serial::set_mode(serial::Mode::Interrupt);
+    instructions::interrupts::enable();
+
     Ok(())
 }
 
+// Calls `entry` with the stack pointer at `top`, abandoning the current stack. Nothing on the old
+// stack is dropped
+unsafe fn switch_stack(top: VirtAddr, entry: fn() -> !) -> ! {
+    // a null frame pointer ends stack walks at `entry`
+    asm!(
+        "mov rsp, {top}",
+        "xor ebp, ebp",
+        "call {entry}",
+        top = in(reg) top.as_u64(),
+        entry = in(reg) entry,
+        options(noreturn),
+    )
+}
+
+// Hands keyboard input to the terminal, sleeping until the next interrupt whenever there's none
+fn event_loop() -> ! {
+    loop {
+        while let Some(event) = keyboard::next_event() {
+            if let Some(command) = terminal::handle_key(event) {
+                run_command(command.trim());
+            }
+        }
+
+        // checking with interrupts off means a key can't slip in between the check and the hlt
+        instructions::interrupts::disable();
+        match keyboard::has_events() {
+            true => instructions::interrupts::enable(),
+            false => instructions::interrupts::enable_and_hlt(),
+        }
+    }
+}
+
+fn run_command(command: &str) {
+    match command {
+        "" => {}
+        "heap" => {
+            println!("heap: {}", heap::statistics());
+            #[cfg(debug_assertions)]
+            println!("{}", heap::diagnostics::statistics());
+        }
+        "frames" => {
+            // printing can allocate, which mustn't find the frame allocator locked
+            let statistics = mem::frame_allocator().statistics();
+            println!("frames: {statistics}");
+        }
+        "regions" => {
+            for region in mem::vmm::regions() {
+                println!("{region}");
+            }
+        }
+        "pci" => device::check_all_buses(&device::PCI_CONFIG, |function| {
+            println!("{function}");
+        }),
+        #[cfg(debug_assertions)]
+        "leaks" => println!("{}", heap::diagnostics::leaks_since(0)),
+        _ => println!("unknown command: {command}"),
+    }
+}
+
 pub fn halt_loop() -> ! {
     loop {
         instructions::hlt()
     }
 }
 
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static PANICKING: AtomicBool = AtomicBool::new(false);
+
+    instructions::interrupts::disable();
+    let registers = cpu::Registers::capture();
+
+    // Whatever held these locks was interrupted by the panic and will never release them
+    unsafe { serial::force_unlock() };
+    serial_println!("KERNEL PANIC: {info}\n{registers}");
+
+    // If printing to the terminal panicked, the serial report is all we're going to get. The
+    // terminal allocates, so it's left alone when the page fault handler panicked too
+    if !PANICKING.swap(true, Ordering::SeqCst) && !idt::page_fault_panicked() {
+        unsafe { terminal::force_unlock() };
+        if terminal::is_initialized() {
+            terminal::print(format_args!("KERNEL PANIC: {info}\n{registers}\n"));
+        }
+    }
+
     halt_loop()
 }
 
 #[cfg(test)]
-fn test_runner(tests: &[&dyn Fn()]) {
-    // println!("running {} tests", tests.len());
-    tests.for_each(|test| test())
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::panic(info)
+}
+
+#[cfg(test)]
+fn test_runner(tests: &[&dyn testing::Testable]) {
+    testing::run(tests)
 }
 
 #[test_case]
@@ -1,49 +1,254 @@
+use super::physical_to_virtual;
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
-
+use core::{fmt, ops::Range};
 use x86_64::{
     structures::paging::{
-        FrameAllocator, PhysFrame, Size4KiB,
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB,
     },
     PhysAddr,
 };
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_regions: &'static MemoryRegions,
+const FRAME_SIZE: u64 = 4096;
+const BITS: usize = u64::BITS as usize;
+
+/// A physical frame allocator keeping one bit per frame, set while the frame is in use.
+///
+/// Single frames come from the first free bit at or after a hint that trails the lowest freed
+/// frame, so allocating doesn't rescan the memory map or the frames handed out before it.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    free: usize,
+    // no frame below this one is free
     next: usize,
 }
 
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the bootloader's memory map, storing the bitmap in the first
+    /// usable region big enough to hold it.
     ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
+    /// This function is unsafe because the caller must guarantee that the passed memory map is
+    /// valid, all frames marked as `USABLE` in it must really be unused. Physical memory must
+    /// already be mapped, see `mem::initialize`.
     pub unsafe fn new(memory_regions: &'static MemoryRegions) -> Self {
-        Self {
-            memory_regions,
+        let usable = || {
+            memory_regions
+                .iter()
+                .filter(|region| region.kind == MemoryRegionKind::Usable)
+                .map(|region| align_up(region.start)..align_down(region.end))
+                .filter(|range| range.start < range.end)
+        };
+
+        let frame_count = usable().map(|range| range.end).max().unwrap_or(0) / FRAME_SIZE;
+        let words = (frame_count as usize + BITS - 1) / BITS;
+        let bitmap_size = align_up((words * 8) as u64);
+        let bitmap_start = usable()
+            .find(|range| range.end - range.start >= bitmap_size)
+            .expect("no usable memory region can hold the frame bitmap")
+            .start;
+
+        let bitmap_pointer = physical_to_virtual(PhysAddr::new(bitmap_start)).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_pointer, words);
+        let mut allocator = Self::from_usable(bitmap, frame_count as usize, usable());
+        allocator.mark_used(bitmap_start..bitmap_start + bitmap_size);
+
+        allocator
+    }
+
+    // Everything starts out used, then each usable range is freed
+    fn from_usable(
+        bitmap: &'static mut [u64],
+        frame_count: usize,
+        usable: impl Iterator<Item = Range<u64>>,
+    ) -> Self {
+        bitmap.fill(u64::MAX);
+        let mut allocator = Self {
+            bitmap,
+            frame_count,
+            free: 0,
             next: 0,
+        };
+
+        for range in usable {
+            (range.start / FRAME_SIZE..range.end / FRAME_SIZE)
+                .for_each(|frame| allocator.set_free(frame as usize));
         }
+        // some firmware reports the first frame usable, but a null physical address is never
+        // something we want to hand out
+        allocator.mark_used(0..FRAME_SIZE);
+        allocator.next = 0;
+
+        allocator
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_regions
-            .iter()
-            .filter_map(|region| match region.kind {
-                MemoryRegionKind::Usable => Some(region.start..region.end),
-                _ => None,
-            }) // usable address ranges
-            .flat_map(|region| region.step_by(4096)) // frame start address
-            .map(|address| PhysFrame::containing_address(PhysAddr::new(address)))
+    /// Allocates `count` physically contiguous frames, the first of them aligned to `alignment`
+    /// bytes.
+    pub fn allocate_frames(
+        &mut self,
+        count: usize,
+        alignment: u64,
+    ) -> Option<PhysFrameRange<Size4KiB>> {
+        // the highest physical address there can be, `PhysAddr::new` rejects anything above it
+        self.allocate_frames_below(count, alignment, PhysAddr::new_truncate(u64::MAX))
+    }
+
+    /// Like `allocate_frames`, but every frame ends at or below `limit`, for devices that can't
+    /// address all of memory.
+    pub fn allocate_frames_below(
+        &mut self,
+        count: usize,
+        alignment: u64,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange<Size4KiB>> {
+        let step = (alignment.max(FRAME_SIZE) / FRAME_SIZE) as usize;
+        let first = (self.next + step - 1) / step * step;
+        let frame_count = self.frame_count.min((limit.as_u64() / FRAME_SIZE) as usize);
+
+        let start = (first..frame_count.saturating_sub(count) + 1)
+            .step_by(step)
+            .find(|&start| (start..start + count).all(|frame| !self.is_used(frame)))?;
+        (start..start + count).for_each(|frame| self.set_used(frame));
+
+        Some(PhysFrame::range(frame_at(start), frame_at(start + count)))
+    }
+
+    /// Returns frames from `allocate_frames`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frames are no longer
+    /// in use.
+    pub unsafe fn deallocate_frames(&mut self, frames: PhysFrameRange<Size4KiB>) {
+        frames.for_each(|frame| self.deallocate_frame(frame));
+    }
+
+    pub fn statistics(&self) -> FrameStatistics {
+        FrameStatistics {
+            total: self.frame_count,
+            free: self.free,
+        }
+    }
+
+    fn mark_used(&mut self, range: Range<u64>) {
+        let end = (align_up(range.end) / FRAME_SIZE).min(self.frame_count as u64);
+        for frame in (range.start / FRAME_SIZE..end).map(|frame| frame as usize) {
+            if !self.is_used(frame) {
+                self.set_used(frame);
+            }
+        }
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        self.bitmap[frame / BITS] |= 1 << (frame % BITS);
+        self.free -= 1;
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        self.bitmap[frame / BITS] &= !(1 << (frame % BITS));
+        self.free += 1;
+        self.next = self.next.min(frame);
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
+        // whole words of used frames are skipped at once
+        let (word, bits) = self
+            .bitmap
+            .iter()
+            .enumerate()
+            .skip(self.next / BITS)
+            .find(|(_, &bits)| bits != u64::MAX)?;
+        let frame = word * BITS + bits.trailing_ones() as usize;
+        if frame >= self.frame_count {
+            return None;
+        }
+
+        self.set_used(frame);
+        self.next = frame + 1;
+
+        Some(frame_at(frame))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let frame = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(
+            frame < self.frame_count && self.is_used(frame),
+            "frame {frame:#x} freed but not allocated"
+        );
+
+        self.set_free(frame);
+    }
+}
+
+/// How much physical memory the frame allocator has left.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStatistics {
+    pub total: usize,
+    pub free: usize,
+}
+
+impl FrameStatistics {
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+impl fmt::Display for FrameStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kib = |frames: usize| frames as u64 * FRAME_SIZE / 1024;
+
+        write!(
+            f,
+            "{} KiB used, {} KiB free of {} KiB",
+            kib(self.used()),
+            kib(self.free),
+            kib(self.total)
+        )
+    }
+}
+
+fn frame_at(index: usize) -> PhysFrame<Size4KiB> {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn align_up(address: u64) -> u64 {
+    (address + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+fn align_down(address: u64) -> u64 {
+    address & !(FRAME_SIZE - 1)
+}
+
+#[test_case]
+fn bitmap_allocates_aligned_runs_and_reuses_frees() {
+    let bitmap = alloc::boxed::Box::leak(vec![0; 2].into_boxed_slice());
+    let usable = [
+        FRAME_SIZE..FRAME_SIZE * 40,
+        FRAME_SIZE * 64..FRAME_SIZE * 128,
+    ];
+    let mut allocator = BitmapFrameAllocator::from_usable(bitmap, 128, usable.into_iter());
+    assert_eq!(allocator.statistics().free, 39 + 64);
+
+    let single = allocator.allocate_frame().unwrap();
+    assert_eq!(single.start_address().as_u64(), FRAME_SIZE);
+
+    // the first 16 frame aligned run of 16 that's free starts at frame 64, past the gap
+    let run = allocator.allocate_frames(16, FRAME_SIZE * 16).unwrap();
+    assert_eq!(run.start.start_address().as_u64(), FRAME_SIZE * 64);
+    // without a limit the frames right up to the end of memory can be used
+    let rest = allocator.allocate_frames(48, FRAME_SIZE).unwrap();
+    assert_eq!(rest.end.start_address().as_u64(), FRAME_SIZE * 128);
 
-        frame
+    unsafe {
+        allocator.deallocate_frames(rest);
+        allocator.deallocate_frames(run);
+        allocator.deallocate_frame(single);
     }
+    assert_eq!(allocator.statistics().free, 39 + 64);
+    assert_eq!(allocator.allocate_frame(), Some(single));
 }
@@ -0,0 +1,163 @@
+use super::{
+    error::{Error, Result},
+    vmm::{self, Caching, Protection},
+};
+use core::ptr;
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, PageSize, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// What a device needs from the memory it's handed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// Alignment of the buffer's physical address, at least a page.
+    pub alignment: u64,
+    /// The buffer has to end at or below this physical address.
+    pub limit: PhysAddr,
+    pub caching: Caching,
+}
+
+impl DmaConstraints {
+    /// For devices that can reach all of memory.
+    pub const ANY: Self = Self {
+        alignment: Size4KiB::SIZE,
+        limit: PhysAddr::new_truncate(u64::MAX),
+        caching: Caching::Uncached,
+    };
+    /// For devices that only take 32-bit addresses.
+    pub const BELOW_4_GIB: Self = Self {
+        limit: PhysAddr::new_truncate(1 << 32),
+        ..Self::ANY
+    };
+}
+
+/// Physically contiguous, zeroed memory a device can read and write directly, mapped where the
+/// kernel can get at it too.
+///
+/// The memory is freed when the buffer is dropped, by which point the device must be done with
+/// it. Until then the bootloader's mapping of it has the buffer's memory type too.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    address: VirtAddr,
+    frames: PhysFrameRange<Size4KiB>,
+    caching: Caching,
+}
+
+impl DmaBuffer {
+    pub fn allocate(size: u64, constraints: DmaConstraints) -> Result<Self> {
+        let count = ((size + Size4KiB::SIZE - 1) / Size4KiB::SIZE).max(1);
+        let frames = super::frame_allocator()
+            .allocate_frames_below(count as usize, constraints.alignment, constraints.limit)
+            .ok_or(Error::OutOfFrames)?;
+
+        let caching = constraints.caching;
+        // write-back is what the bootloader mapped everything as, so nothing changes for it
+        let retyped = caching != Caching::WriteBack;
+        let switched = match retyped {
+            true => vmm::set_physical_mapping_caching(frames, caching),
+            false => Ok(()),
+        };
+        let mapped = switched.and_then(|()| {
+            vmm::map_physical(
+                frames.start.start_address(),
+                count * Size4KiB::SIZE,
+                Protection::READ_WRITE,
+                caching,
+            )
+        });
+        let address = match mapped {
+            Ok(address) => address,
+            Err(error) => {
+                if retyped {
+                    // this stops at the first frame that wasn't switched over, which is fine
+                    let _ = vmm::set_physical_mapping_caching(frames, Caching::WriteBack);
+                }
+                unsafe { super::frame_allocator().deallocate_frames(frames) };
+                return Err(error);
+            }
+        };
+
+        let buffer = Self {
+            address,
+            frames,
+            caching,
+        };
+        unsafe { ptr::write_bytes(buffer.as_mut_ptr::<u8>(), 0, buffer.size() as usize) };
+
+        Ok(buffer)
+    }
+
+    pub fn virtual_address(&self) -> VirtAddr {
+        self.address
+    }
+
+    /// What to give the device.
+    pub fn physical_address(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.frames.count() as u64 * Size4KiB::SIZE
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.address.as_ptr()
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.address.as_mut_ptr()
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // the mapping goes first so nothing can reach the frames once they're free
+        vmm::unmap(self.address).expect("DMA buffer mapping went missing");
+        if self.caching != Caching::WriteBack {
+            vmm::set_physical_mapping_caching(self.frames, Caching::WriteBack)
+                .expect("DMA buffer frames went missing from the physical memory mapping");
+        }
+        unsafe { super::frame_allocator().deallocate_frames(self.frames) };
+    }
+}
+
+#[test_case]
+fn dma_buffers_are_contiguous_and_within_their_limit() {
+    use x86_64::structures::paging::{mapper::TranslateResult, PageTableFlags, Translate};
+
+    let constraints = DmaConstraints {
+        alignment: 64 * 1024,
+        caching: Caching::WriteCombining,
+        ..DmaConstraints::BELOW_4_GIB
+    };
+    let buffer = DmaBuffer::allocate(3 * Size4KiB::SIZE - 100, constraints).unwrap();
+
+    let physical = buffer.physical_address();
+    assert!(physical.is_aligned(64 * 1024u64));
+    assert!(physical.as_u64() + buffer.size() <= 1 << 32);
+    let offset = super::physical_to_virtual(PhysAddr::zero());
+    for page in 0..3 {
+        let address = buffer.virtual_address() + page * Size4KiB::SIZE;
+        let translated = unsafe { super::translate_address(address, offset) }.unwrap();
+        assert_eq!(translated, physical + page * Size4KiB::SIZE);
+    }
+
+    let memory = unsafe { core::slice::from_raw_parts(buffer.as_ptr::<u8>(), 3 * 4096) };
+    assert!(memory.iter().all(|&byte| byte == 0));
+
+    // the bootloader's mapping of the buffer is write combining too, until it's dropped
+    let alias_caching = || match super::mapper().translate(super::physical_to_virtual(physical)) {
+        TranslateResult::Mapped { flags, .. } => {
+            flags & (PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE)
+        }
+        _ => panic!("the physical memory mapping has a hole"),
+    };
+    assert_eq!(alias_caching(), PageTableFlags::NO_CACHE);
+
+    // page tables stay around, only the buffer's own frames come back
+    let free_before = super::frame_allocator().statistics().free;
+    drop(buffer);
+    assert_eq!(super::frame_allocator().statistics().free, free_before + 3);
+    assert_eq!(alias_caching(), PageTableFlags::empty());
+}
@@ -1,6 +1,9 @@
 use core::fmt::Display;
 use thiserror::Error;
-use x86_64::structures::paging::{mapper, page_table, PageSize, Size4KiB};
+use x86_64::{
+    structures::paging::{mapper, page_table, PageSize, Size4KiB},
+    VirtAddr,
+};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -11,7 +14,25 @@ pub enum Error {
     #[error(transparent)]
     MapTo(MapToError<Size4KiB>),
     #[error(transparent)]
+    Unmap(UnmapError),
+    #[error(transparent)]
     PhysicalMemoryOffset(#[from] PhysicalMemoryOffsetError),
+    #[error(transparent)]
+    FlagUpdate(FlagUpdateError),
+    #[error("Out of physical memory")]
+    OutOfFrames,
+    #[error("No free virtual range of {0:#x} bytes")]
+    OutOfAddressSpace(u64),
+    #[error("{start:?} to {end:?} overlaps a region already in use")]
+    Overlap { start: VirtAddr, end: VirtAddr },
+    #[error("Nothing is mapped at {0:?}")]
+    NotMapped(VirtAddr),
+    #[error("{0:?} is in a stack, which can only be read-write")]
+    StackProtection(VirtAddr),
+    #[error("Kernel image isn't an ELF file")]
+    KernelImage,
+    #[error("Memory manager is locked by the faulting code")]
+    Locked,
 }
 
 impl From<page_table::FrameError> for Error {
@@ -26,6 +47,18 @@ impl From<mapper::MapToError<Size4KiB>> for Error {
     }
 }
 
+impl From<mapper::UnmapError> for Error {
+    fn from(value: mapper::UnmapError) -> Self {
+        Self::Unmap(UnmapError::from(value))
+    }
+}
+
+impl From<mapper::FlagUpdateError> for Error {
+    fn from(value: mapper::FlagUpdateError) -> Self {
+        Self::FlagUpdate(FlagUpdateError::from(value))
+    }
+}
+
 #[derive(Debug, Error)]
 pub struct FrameError(page_table::FrameError);
 
@@ -39,7 +72,7 @@ impl Display for FrameError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let description = match self.0 {
             page_table::FrameError::FrameNotPresent => "frame not present",
-            page_table::FrameError::HugeFrame => "huge pages not supported",
+            page_table::FrameError::HugeFrame => "mapped by a huge page",
         };
 
         write!(f, "Frame error: {description}")
@@ -56,6 +89,26 @@ impl<S: PageSize> From<mapper::MapToError<S>> for MapToError<S> {
     }
 }
 
+#[derive(Debug, Error)]
+#[error("{0:?}")]
+pub struct UnmapError(mapper::UnmapError);
+
+impl From<mapper::UnmapError> for UnmapError {
+    fn from(value: mapper::UnmapError) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{0:?}")]
+pub struct FlagUpdateError(mapper::FlagUpdateError);
+
+impl From<mapper::FlagUpdateError> for FlagUpdateError {
+    fn from(value: mapper::FlagUpdateError) -> Self {
+        Self(value)
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("Physical memory offset not set")]
 pub struct PhysicalMemoryOffsetError;
@@ -0,0 +1,410 @@
+use super::DIAGNOSTICS;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::asm,
+    fmt,
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+const POISON_BYTE: u8 = 0xdd;
+const ALIVE: u64 = 0xa110_ca7e_d000_0000;
+const FREED: u64 = 0xf4ee_d000_0000_0000;
+// Powers of two from 16 bytes to 16 KiB, the last one counts everything bigger
+const SIZE_CLASSES: usize = 12;
+const CALL_SITE_DEPTH: usize = 4;
+// The frames of the allocator itself, `Diagnostics::alloc` and the allocator shim calling it
+const SKIPPED_FRAMES: usize = 2;
+const LEAKS_SHOWN: usize = 16;
+
+/// Counts of what the global allocator has handed out, by size.
+pub fn statistics() -> AllocationStatistics {
+    DIAGNOSTICS.statistics()
+}
+
+/// Turns recording where each allocation was made on or off, which needs a walk up the stack on
+/// every allocation. It starts out on.
+pub fn set_call_site_tracking(enabled: bool) {
+    DIAGNOSTICS.set_call_site_tracking(enabled)
+}
+
+/// Marks a point in time that `leaks_since` can list allocations from.
+pub fn checkpoint() -> u64 {
+    DIAGNOSTICS.checkpoint()
+}
+
+/// Lists what's still allocated out of everything allocated after `checkpoint`, use `0` for
+/// everything since boot.
+pub fn leaks_since(checkpoint: u64) -> LeakReport {
+    DIAGNOSTICS.leaks_since(checkpoint)
+}
+
+/// Sits in front of every allocation, followed by a red zone, the allocation itself and another
+/// red zone. Allocations still in use are linked together so leaks can be listed.
+#[repr(C)]
+struct Header {
+    previous: Option<NonNull<Header>>,
+    next: Option<NonNull<Header>>,
+    size: usize,
+    sequence: u64,
+    call_sites: [usize; CALL_SITE_DEPTH],
+    // last, the wrapped allocator keeps its free list at the start of a freed block
+    magic: u64,
+}
+
+/// Wraps an allocator to count allocations by size class, catch writes past either end of an
+/// allocation when it's freed, poison freed memory and keep track of what's still allocated.
+pub struct Diagnostics<A: 'static> {
+    heap: &'static A,
+    state: Mutex<State>,
+}
+
+struct State {
+    classes: [SizeClass; SIZE_CLASSES],
+    live: Option<NonNull<Header>>,
+    sequence: u64,
+    track_call_sites: bool,
+}
+
+// The live list only points into memory the wrapped allocator handed out
+unsafe impl Send for State {}
+
+impl<A: 'static> Diagnostics<A> {
+    pub const fn new(heap: &'static A) -> Self {
+        Self {
+            heap,
+            state: Mutex::new(State {
+                classes: [SizeClass::EMPTY; SIZE_CLASSES],
+                live: None,
+                sequence: 0,
+                track_call_sites: true,
+            }),
+        }
+    }
+
+    fn statistics(&self) -> AllocationStatistics {
+        AllocationStatistics {
+            classes: self.lock(|state| state.classes),
+        }
+    }
+
+    fn set_call_site_tracking(&self, enabled: bool) {
+        self.lock(|state| state.track_call_sites = enabled);
+    }
+
+    fn checkpoint(&self) -> u64 {
+        self.lock(|state| state.sequence)
+    }
+
+    fn leaks_since(&self, checkpoint: u64) -> LeakReport {
+        // nothing can be printed while the state is locked, printing allocates
+        self.lock(|state| {
+            let mut report = LeakReport {
+                shown: [None; LEAKS_SHOWN],
+                count: 0,
+                bytes: 0,
+            };
+
+            let mut next = state.live;
+            while let Some(header) = next {
+                let header = unsafe { header.as_ref() };
+                // the list is newest first
+                if header.sequence < checkpoint {
+                    break;
+                }
+
+                if let Some(slot) = report.shown.get_mut(report.count) {
+                    *slot = Some(Leak {
+                        size: header.size,
+                        call_sites: header.call_sites,
+                    });
+                }
+                report.count += 1;
+                report.bytes += header.size;
+                next = header.next;
+            }
+
+            report
+        })
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        // interrupt handlers allocate too
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Diagnostics<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (block_layout, offset) = block_layout(layout);
+        let block = self.heap.alloc(block_layout);
+        if block.is_null() {
+            return block;
+        }
+
+        let pointer = block.add(offset);
+        ptr::write_bytes(pointer.sub(RED_ZONE), RED_ZONE_BYTE, RED_ZONE);
+        ptr::write_bytes(pointer.add(layout.size()), RED_ZONE_BYTE, RED_ZONE);
+
+        let header = header(pointer);
+        let track_call_sites = self.lock(|state| state.track_call_sites);
+        let call_sites = match track_call_sites {
+            true => call_sites(),
+            false => [0; CALL_SITE_DEPTH],
+        };
+
+        self.lock(|state| {
+            state.classes[size_class(layout.size())].allocated(layout.size());
+            header.as_ptr().write(Header {
+                previous: None,
+                next: state.live,
+                size: layout.size(),
+                sequence: state.sequence,
+                call_sites,
+                magic: ALIVE,
+            });
+            if let Some(mut next) = state.live {
+                next.as_mut().previous = Some(header);
+            }
+            state.live = Some(header);
+            state.sequence += 1;
+        });
+
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        let mut header = header(pointer);
+        let header_ref = header.as_mut();
+        match header_ref.magic {
+            ALIVE => {}
+            FREED => panic!("{layout:?} at {pointer:p} freed twice"),
+            _ => panic!("{layout:?} at {pointer:p} freed but not allocated"),
+        }
+
+        let front = core::slice::from_raw_parts(pointer.sub(RED_ZONE), RED_ZONE);
+        let back = core::slice::from_raw_parts(pointer.add(layout.size()), RED_ZONE);
+        let overrun = !front.iter().chain(back).all(|&byte| byte == RED_ZONE_BYTE);
+
+        self.lock(|state| {
+            state.classes[size_class(layout.size())].freed(layout.size());
+            match header_ref.previous {
+                Some(mut previous) => previous.as_mut().next = header_ref.next,
+                None => state.live = header_ref.next,
+            }
+            if let Some(mut next) = header_ref.next {
+                next.as_mut().previous = header_ref.previous;
+            }
+        });
+
+        // whatever's around an overrun might be damaged too, so the block is left alone
+        if overrun {
+            panic!(
+                "heap overrun around {layout:?} at {pointer:p}, allocated from {}",
+                CallSites(&header_ref.call_sites)
+            );
+        }
+
+        header_ref.magic = FREED;
+        ptr::write_bytes(
+            pointer.sub(RED_ZONE),
+            POISON_BYTE,
+            layout.size() + RED_ZONE * 2,
+        );
+        let (block_layout, offset) = block_layout(layout);
+        self.heap.dealloc(pointer.sub(offset), block_layout);
+    }
+}
+
+/// How many allocations of each size have been made and freed.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClass {
+    pub allocations: usize,
+    pub frees: usize,
+    /// Bytes still allocated.
+    pub bytes: usize,
+}
+
+impl SizeClass {
+    const EMPTY: Self = Self {
+        allocations: 0,
+        frees: 0,
+        bytes: 0,
+    };
+
+    fn allocated(&mut self, size: usize) {
+        self.allocations += 1;
+        self.bytes += size;
+    }
+
+    fn freed(&mut self, size: usize) {
+        self.frees += 1;
+        self.bytes -= size;
+    }
+}
+
+pub struct AllocationStatistics {
+    pub classes: [SizeClass; SIZE_CLASSES],
+}
+
+impl fmt::Display for AllocationStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "size      allocations  frees        live bytes")?;
+        for (i, class) in self.classes.iter().enumerate() {
+            let limit = 16 << i;
+            match i {
+                _ if i == SIZE_CLASSES - 1 => write!(f, "\n> {:<7}", limit / 2)?,
+                _ => write!(f, "\n<= {limit:<6}")?,
+            }
+            write!(
+                f,
+                " {:<12} {:<12} {}",
+                class.allocations, class.frees, class.bytes
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Leak {
+    size: usize,
+    call_sites: [usize; CALL_SITE_DEPTH],
+}
+
+/// What's still allocated since a checkpoint, the most recent allocations first.
+pub struct LeakReport {
+    shown: [Option<Leak>; LEAKS_SHOWN],
+    pub count: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} allocations, {} bytes still in use",
+            self.count, self.bytes
+        )?;
+        for leak in self.shown.iter().flatten() {
+            write!(
+                f,
+                "\n{} bytes from {}",
+                leak.size,
+                CallSites(&leak.call_sites)
+            )?;
+        }
+        if self.count > LEAKS_SHOWN {
+            write!(f, "\n...and {} more", self.count - LEAKS_SHOWN)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct CallSites<'a>(&'a [usize; CALL_SITE_DEPTH]);
+
+impl fmt::Display for CallSites<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sites = self.0.iter().take_while(|&&site| site != 0);
+        match sites.next() {
+            Some(site) => write!(f, "{site:#x}")?,
+            None => return write!(f, "an unknown call site"),
+        }
+        sites.try_for_each(|site| write!(f, " <- {site:#x}"))
+    }
+}
+
+// The wrapped allocation, with the header and red zones around it, and where in it the caller's
+// allocation starts
+fn block_layout(layout: Layout) -> (Layout, usize) {
+    let align = layout.align().max(align_of::<Header>());
+    let offset = (size_of::<Header>() + RED_ZONE + align - 1) / align * align;
+    let size = offset + layout.size() + RED_ZONE;
+
+    (Layout::from_size_align(size, align).unwrap(), offset)
+}
+
+fn header(pointer: *mut u8) -> NonNull<Header> {
+    let header = unsafe { pointer.sub(RED_ZONE + size_of::<Header>()) };
+
+    NonNull::new(header.cast()).unwrap()
+}
+
+fn size_class(size: usize) -> usize {
+    let class = size.max(16).next_power_of_two().trailing_zeros() as usize - 4;
+
+    class.min(SIZE_CLASSES - 1)
+}
+
+// Return addresses up the stack from the allocator, found by following saved frame pointers.
+// The kernel is built with frame pointers, see `.cargo/config.toml`
+#[inline(never)]
+fn call_sites() -> [usize; CALL_SITE_DEPTH] {
+    let mut sites = [0; CALL_SITE_DEPTH];
+    let mut frame: *const usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack)) };
+
+    for i in 0..SKIPPED_FRAMES + CALL_SITE_DEPTH {
+        // callers' frames are further up the stack, anything else means the chain has ended
+        if frame.is_null() || frame as usize % 8 != 0 {
+            break;
+        }
+        let (next, return_address) = unsafe { (*frame as *const usize, *frame.add(1)) };
+        if let Some(site) = i.checked_sub(SKIPPED_FRAMES) {
+            sites[site] = return_address;
+        }
+        if next <= frame || next as usize - frame as usize > 1024 * 1024 {
+            break;
+        }
+        frame = next;
+    }
+
+    sites
+}
+
+#[test_case]
+fn freed_allocations_are_tracked_by_size() {
+    // timer callbacks mustn't allocate in the middle of this
+    interrupts::without_interrupts(|| {
+        let checkpoint = checkpoint();
+        let before = statistics().classes[size_class(100)];
+
+        let buffer = alloc::vec![0u8; 100];
+        let during = statistics().classes[size_class(100)];
+        assert_eq!(leaks_since(checkpoint).count, 1);
+        drop(buffer);
+        let after = statistics().classes[size_class(100)];
+
+        assert_eq!(during.allocations, before.allocations + 1);
+        assert_eq!(during.bytes, before.bytes + 100);
+        assert_eq!(after.frees, before.frees + 1);
+        assert_eq!(after.bytes, before.bytes);
+        assert_eq!(leaks_since(checkpoint).count, 0);
+    })
+}
+
+#[cfg(test)]
+crate::should_panic! {
+    fn writing_past_an_allocation_panics_when_it_is_freed() {
+        let mut buffer = alloc::vec![0u8; 8];
+        unsafe { buffer.as_mut_ptr().add(8).write(1) };
+    }
+}
+
+#[cfg(test)]
+crate::should_panic! {
+    fn freeing_an_allocation_twice_panics() {
+        let layout = Layout::new::<u64>();
+        unsafe {
+            let pointer = alloc::alloc::alloc(layout);
+            alloc::alloc::dealloc(pointer, layout);
+            alloc::alloc::dealloc(pointer, layout);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use super::HeapBackend;
+use core::{alloc::Layout, ptr::NonNull};
+use linked_list_allocator::Heap;
+
+// Each block is aligned to its size, so the sizes must be powers of two. The linked list heap
+// never hands out less than 16 bytes, so neither do we
+const BLOCK_SIZES: [usize; 10] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192];
+
+struct Node {
+    next: Option<NonNull<Node>>,
+}
+
+/// Rounds small allocations up to a fixed set of block sizes and keeps a free list per size, so
+/// allocating and freeing them is a push or pop. Blocks are carved out of a linked list heap,
+/// which also serves anything bigger than the largest block.
+///
+/// Freed blocks stay on their list rather than going back to the linked list heap.
+pub struct FixedSizeBlockHeap {
+    lists: [Option<NonNull<Node>>; BLOCK_SIZES.len()],
+    fallback: Heap,
+    // bytes sitting in the free lists
+    cached: usize,
+}
+
+// The free lists only point into the heap's own memory
+unsafe impl Send for FixedSizeBlockHeap {}
+
+impl FixedSizeBlockHeap {
+    pub const fn empty() -> Self {
+        Self {
+            lists: [None; BLOCK_SIZES.len()],
+            fallback: Heap::empty(),
+            cached: 0,
+        }
+    }
+}
+
+impl HeapBackend for FixedSizeBlockHeap {
+    unsafe fn initialize(&mut self, bottom: *mut u8, size: usize) {
+        self.fallback.init(bottom, size)
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback.extend(by)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let Some(index) = list_index(layout) else {
+            return self.fallback.allocate_first_fit(layout).ok();
+        };
+
+        match self.lists[index] {
+            Some(node) => {
+                self.lists[index] = unsafe { node.as_ref().next };
+                self.cached -= BLOCK_SIZES[index];
+                Some(node.cast())
+            }
+            None => self
+                .fallback
+                .allocate_first_fit(self.backing_layout(layout))
+                .ok(),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, pointer: NonNull<u8>, layout: Layout) {
+        let Some(index) = list_index(layout) else {
+            return self.fallback.deallocate(pointer, layout);
+        };
+
+        let node = pointer.cast::<Node>();
+        node.as_ptr().write(Node {
+            next: self.lists[index],
+        });
+        self.lists[index] = Some(node);
+        self.cached += BLOCK_SIZES[index];
+    }
+
+    fn backing_layout(&self, layout: Layout) -> Layout {
+        let Some(index) = list_index(layout) else {
+            return layout;
+        };
+        let size = BLOCK_SIZES[index];
+
+        Layout::from_size_align(size, size).unwrap()
+    }
+
+    fn size(&self) -> usize {
+        self.fallback.size()
+    }
+
+    fn used(&self) -> usize {
+        self.fallback.used() - self.cached
+    }
+}
+
+// The smallest block that fits the layout, or `None` if it has to go to the fallback heap
+fn list_index(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+
+    BLOCK_SIZES
+        .iter()
+        .position(|&block_size| block_size >= size)
+}
@@ -0,0 +1,392 @@
+#[cfg(debug_assertions)]
+pub mod diagnostics;
+#[cfg(any(feature = "fixed-size-block-heap", test))]
+mod fixed_size_block;
+#[cfg(any(feature = "slab-heap", test))]
+mod slab;
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+pub const HEAP_BOTTOM: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+/// How far the heap may ever grow, the virtual address range above `HEAP_BOTTOM` it owns. Past
+/// the initial `HEAP_SIZE`, pages in it are only backed when they're first touched.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024; // 64 MiB
+
+// The heap grows by at least this much at a time, so small allocations don't each extend it
+const GROWTH_STEP: usize = 64 * 1024;
+
+// Debug builds check every allocation and keep count of them
+#[cfg(debug_assertions)]
+#[global_allocator]
+static DIAGNOSTICS: diagnostics::Diagnostics<InterruptSafeHeap> =
+    diagnostics::Diagnostics::new(&ALLOCATOR);
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_HEAP_LIMIT);
+
+#[cfg(all(feature = "fixed-size-block-heap", feature = "slab-heap"))]
+compile_error!("only one of the fixed-size-block-heap and slab-heap features can be enabled");
+
+// The linked list heap is used unless a feature picks another design
+#[cfg(feature = "fixed-size-block-heap")]
+type Backend = fixed_size_block::FixedSizeBlockHeap;
+#[cfg(feature = "slab-heap")]
+type Backend = slab::SlabHeap;
+#[cfg(not(any(feature = "fixed-size-block-heap", feature = "slab-heap")))]
+type Backend = Heap;
+
+#[cfg_attr(not(debug_assertions), global_allocator)]
+static ALLOCATOR: InterruptSafeHeap = InterruptSafeHeap(Mutex::new(Backend::empty()));
+
+/// Keeps interrupts disabled while the heap is locked, so interrupt handlers (timer callbacks in
+/// particular) can allocate and free without deadlocking against the code they interrupted.
+///
+/// When an allocation doesn't fit, the heap grows upwards until it does or the heap reaches its
+/// limit.
+struct InterruptSafeHeap(Mutex<Backend>);
+
+unsafe impl GlobalAlloc for InterruptSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            let mut heap = self.0.lock();
+            if let Some(pointer) = heap.allocate(layout) {
+                return pointer.as_ptr();
+            }
+
+            grow(&mut *heap, layout);
+            heap.allocate(layout)
+                .map_or(ptr::null_mut(), NonNull::as_ptr)
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| {
+            self.0
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout)
+        })
+    }
+}
+
+pub fn initialize() -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = super::mapper();
+    let mut frame_allocator = super::frame_allocator();
+    let heap_start = VirtAddr::new(HEAP_BOTTOM as u64);
+    map_pages(&mut *mapper, &mut *frame_allocator, heap_start, HEAP_SIZE)?;
+
+    unsafe {
+        ALLOCATOR
+            .0
+            .lock()
+            .initialize(HEAP_BOTTOM as *mut u8, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+/// Caps how large the heap may grow, rounded down to a page and clamped to `HEAP_MAX_SIZE`.
+/// Memory the heap already spans is kept even if it's past the new limit.
+pub fn set_limit(limit: usize) {
+    // the heap only ever grows by whole pages, so its top stays on a page boundary
+    let limit = limit & !(Size4KiB::SIZE as usize - 1);
+    HEAP_LIMIT.store(limit.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+pub fn statistics() -> HeapStatistics {
+    interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+
+        HeapStatistics {
+            size: heap.size(),
+            used: heap.used(),
+            limit: HEAP_LIMIT.load(Ordering::Relaxed),
+        }
+    })
+}
+
+/// What the global allocator needs from a heap design. A heap manages one range of memory that
+/// only ever grows upwards.
+pub trait HeapBackend {
+    /// This function is unsafe because the caller must guarantee that `size` bytes from `bottom`
+    /// are mapped and unused, and that this is only called once.
+    unsafe fn initialize(&mut self, bottom: *mut u8, size: usize);
+
+    /// Grows the heap by the `by` bytes right after its end.
+    ///
+    /// This function is unsafe because the caller must guarantee that the added memory is unused
+    /// and either mapped or backed when it's first touched.
+    unsafe fn extend(&mut self, by: usize);
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// This function is unsafe because the caller must guarantee that `pointer` came from
+    /// `allocate` with the same `layout`, and isn't used afterwards.
+    unsafe fn deallocate(&mut self, pointer: NonNull<u8>, layout: Layout);
+
+    /// The block `allocate` carves out of the heap's memory to serve `layout`, which is what the
+    /// heap has to grow enough to fit when it's out of room.
+    fn backing_layout(&self, layout: Layout) -> Layout;
+
+    /// How many bytes the heap spans.
+    fn size(&self) -> usize;
+
+    /// How many bytes can't currently be handed out, whether they're allocated or spent on
+    /// bookkeeping.
+    fn used(&self) -> usize;
+}
+
+impl HeapBackend for Heap {
+    unsafe fn initialize(&mut self, bottom: *mut u8, size: usize) {
+        self.init(bottom, size)
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        Heap::extend(self, by)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        self.allocate_first_fit(layout).ok()
+    }
+
+    unsafe fn deallocate(&mut self, pointer: NonNull<u8>, layout: Layout) {
+        Heap::deallocate(self, pointer, layout)
+    }
+
+    fn backing_layout(&self, layout: Layout) -> Layout {
+        layout
+    }
+
+    fn size(&self) -> usize {
+        Heap::size(self)
+    }
+
+    fn used(&self) -> usize {
+        Heap::used(self)
+    }
+}
+
+/// How large the heap has grown and how much of it is in use.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStatistics {
+    pub size: usize,
+    pub used: usize,
+    pub limit: usize,
+}
+
+impl HeapStatistics {
+    pub fn free(&self) -> usize {
+        self.size - self.used
+    }
+}
+
+impl fmt::Display for HeapStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB used, {} KiB free, {} KiB in the heap of {} KiB allowed",
+            self.used / 1024,
+            self.free() / 1024,
+            self.size / 1024,
+            self.limit / 1024
+        )
+    }
+}
+
+// Extends the heap far enough for `layout` to fit. Nothing is mapped here, the page fault handler
+// backs each new page the first time it's touched, see `vmm::handle_fault`.
+fn grow(heap: &mut impl HeapBackend, layout: Layout) {
+    let layout = heap.backing_layout(layout);
+    let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+    // the free block at the top may be too small to help, so don't count on it
+    let needed = (layout.size() + layout.align()).max(GROWTH_STEP);
+    let size = align_up(needed).min(limit.saturating_sub(heap.size()));
+    if size < layout.size() {
+        return;
+    }
+
+    unsafe { heap.extend(size) };
+}
+
+fn map_pages(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    start: VirtAddr,
+    size: usize,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1u64);
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    Ok(())
+}
+
+fn align_up(size: usize) -> usize {
+    let page_size = Size4KiB::SIZE as usize;
+
+    (size + page_size - 1) & !(page_size - 1)
+}
+
+#[alloc_error_handler]
+fn allocation_failed(layout: Layout) -> ! {
+    panic!("failed to allocate {layout:?}, heap: {}", statistics())
+}
+
+#[test_case]
+fn heap_grows_past_its_initial_size() {
+    let before = statistics().size;
+    let buffer = alloc::vec![0u8; HEAP_SIZE * 2];
+
+    assert_eq!(buffer.len(), HEAP_SIZE * 2);
+    assert!(statistics().size > before);
+
+    set_limit(DEFAULT_HEAP_LIMIT + 100);
+    assert_eq!(statistics().limit, DEFAULT_HEAP_LIMIT);
+    set_limit(DEFAULT_HEAP_LIMIT);
+}
+
+#[test_case]
+fn heap_growth_is_backed_on_first_touch() {
+    let free_frames = || super::frame_allocator().statistics().free;
+    let pages = |bytes| bytes / Size4KiB::SIZE as usize;
+    // more than is free, so the heap has to grow
+    let size = statistics().free() + HEAP_SIZE * 4;
+
+    let before = free_frames();
+    let mut buffer = alloc::vec::Vec::<u8>::with_capacity(size);
+    assert!(before - free_frames() < pages(HEAP_SIZE));
+    buffer.resize(size, 1);
+    assert!(before - free_frames() >= pages(HEAP_SIZE * 4));
+}
+
+#[test_case]
+fn interrupt_handlers_can_grow_the_heap_while_the_mapper_is_held() {
+    use crate::timer;
+    use core::{sync::atomic::AtomicBool, time::Duration};
+
+    static GREW: AtomicBool = AtomicBool::new(false);
+    // more than is free, so the callback grows the heap onto pages that aren't backed yet
+    let size = statistics().free() + HEAP_SIZE;
+    timer::after(Duration::from_millis(1), move || {
+        let buffer = alloc::vec![1u8; size];
+        GREW.store(buffer[size - 1] == 1, Ordering::Relaxed);
+    });
+
+    let mapper = super::mapper();
+    timer::sleep(Duration::from_millis(5));
+    drop(mapper);
+    timer::sleep(Duration::from_millis(5));
+
+    assert!(GREW.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn growth_fits_the_block_a_backend_carves_out() {
+    const ARENA_SIZE: usize = 256 * 1024;
+
+    let arena_layout = Layout::from_size_align(ARENA_SIZE, 64 * 1024).unwrap();
+    let arena = unsafe { alloc::alloc::alloc(arena_layout) };
+    assert!(!arena.is_null());
+    let mut heap = slab::SlabHeap::empty();
+    // the top isn't 64 KiB aligned and everything below it is in use
+    let bottom = Layout::from_size_align(3 * 4096, 8).unwrap();
+    unsafe { heap.initialize(arena, bottom.size()) };
+    heap.allocate(bottom).unwrap();
+
+    // 8 KiB objects come from 64 KiB slabs aligned to their size
+    let object = Layout::from_size_align(8192, 8).unwrap();
+    assert!(heap.allocate(object).is_none());
+    grow(&mut heap, object);
+    assert!(heap.size() <= ARENA_SIZE);
+    assert!(heap.allocate(object).is_some());
+
+    unsafe { alloc::alloc::dealloc(arena, arena_layout) };
+}
+
+// Runs the terminal's allocation pattern against a fresh heap in a scratch arena, returning how
+// many cycles it took: a glyph's pixel map is built and dropped for every character, and the
+// finished lines are kept as boxed strings in a capped buffer that drops the oldest
+#[cfg(test)]
+fn benchmark(mut heap: impl HeapBackend) -> u64 {
+    use crate::graphics::Pixel;
+    use core::arch::x86_64::_rdtsc;
+
+    const ARENA_SIZE: usize = 256 * 1024;
+    const CHARACTERS: usize = 4000;
+    const LINES: usize = 64;
+
+    let arena_layout = Layout::from_size_align(ARENA_SIZE, 4096).unwrap();
+    let arena = unsafe { alloc::alloc::alloc(arena_layout) };
+    assert!(!arena.is_null());
+    unsafe { heap.initialize(arena, ARENA_SIZE) };
+    let used_before = heap.used();
+
+    // a freed block is handed out again for the next allocation of its size
+    let small = Layout::new::<[u64; 4]>();
+    let first = heap.allocate(small).unwrap();
+    unsafe { heap.deallocate(first, small) };
+    let second = heap.allocate(small).unwrap();
+    assert_eq!(first, second);
+    unsafe { heap.deallocate(second, small) };
+
+    let mut lines = [None; LINES];
+    let start = unsafe { _rdtsc() };
+    for i in 0..CHARACTERS {
+        let pixels = Layout::array::<Pixel>(200 + i * 37 % 400).unwrap();
+        let glyph = heap.allocate(pixels).expect("benchmark arena ran out");
+        unsafe { heap.deallocate(glyph, pixels) };
+
+        // a line ends every 40 characters or so
+        if i % 40 == 39 {
+            let line = Layout::array::<u8>(16 + i * 7 % 100).unwrap();
+            let slot = i / 40 % LINES;
+            if let Some((old, layout)) = lines[slot].take() {
+                unsafe { heap.deallocate(old, layout) };
+            }
+            lines[slot] = Some((heap.allocate(line).expect("benchmark arena ran out"), line));
+        }
+    }
+    lines
+        .iter_mut()
+        .filter_map(Option::take)
+        .for_each(|(line, layout)| unsafe { heap.deallocate(line, layout) });
+    let cycles = unsafe { _rdtsc() } - start;
+
+    // everything was freed, though a design may hold on to some bookkeeping
+    assert!(heap.used() - used_before < ARENA_SIZE / 8);
+    unsafe { alloc::alloc::dealloc(arena, arena_layout) };
+
+    cycles
+}
+
+#[test_case]
+fn heap_designs_reuse_memory_under_the_terminal_pattern() {
+    let linked_list = benchmark(Heap::empty());
+    let fixed_size_block = benchmark(fixed_size_block::FixedSizeBlockHeap::empty());
+    let slab = benchmark(slab::SlabHeap::empty());
+
+    crate::serial_print!(
+        "(cycles: linked list {linked_list}, fixed size block {fixed_size_block}, slab {slab}) "
+    );
+}
@@ -0,0 +1,209 @@
+use super::HeapBackend;
+use core::{alloc::Layout, mem::size_of, ptr::NonNull};
+use linked_list_allocator::Heap;
+
+// Each object is aligned to its size, so the sizes must be powers of two
+const OBJECT_SIZES: [usize; 10] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192];
+const PAGE_SIZE: usize = 4096;
+// Slabs of big objects grow past a page so they still hold a few
+const MIN_OBJECTS: usize = 8;
+
+struct Object {
+    next: Option<NonNull<Object>>,
+}
+
+// Sits at the start of its slab, which is aligned to its size so an object's slab can be found
+// from the object's address
+struct Slab {
+    previous: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free: Option<NonNull<Object>>,
+    in_use: usize,
+}
+
+/// Serves small allocations from slabs, blocks carved into objects of one size with their own
+/// free list. Slabs come from a linked list heap, which also serves anything bigger than the
+/// largest object.
+///
+/// Unlike `FixedSizeBlockHeap`, a slab whose objects are all freed is handed back to the linked
+/// list heap, unless it's the last one with room for its object size.
+pub struct SlabHeap {
+    // slabs with at least one free object, per object size
+    partial: [Option<NonNull<Slab>>; OBJECT_SIZES.len()],
+    fallback: Heap,
+    // bytes of free objects in the slabs
+    cached: usize,
+}
+
+// The slab lists only point into the heap's own memory
+unsafe impl Send for SlabHeap {}
+
+impl SlabHeap {
+    pub const fn empty() -> Self {
+        Self {
+            partial: [None; OBJECT_SIZES.len()],
+            fallback: Heap::empty(),
+            cached: 0,
+        }
+    }
+
+    fn new_slab(&mut self, index: usize) -> Option<NonNull<Slab>> {
+        let object_size = OBJECT_SIZES[index];
+        let slab = self
+            .fallback
+            .allocate_first_fit(slab_layout(object_size))
+            .ok()?
+            .cast::<Slab>();
+
+        // objects are linked in address order, the first one at the head
+        let mut free = None;
+        for i in (0..capacity(object_size)).rev() {
+            let offset = first_object(object_size) + i * object_size;
+            let object = unsafe { slab.cast::<u8>().as_ptr().add(offset) }.cast::<Object>();
+            unsafe { object.write(Object { next: free }) };
+            free = NonNull::new(object);
+        }
+
+        unsafe {
+            slab.as_ptr().write(Slab {
+                previous: None,
+                next: None,
+                free,
+                in_use: 0,
+            })
+        };
+        self.cached += capacity(object_size) * object_size;
+        self.push(index, slab);
+
+        Some(slab)
+    }
+
+    fn push(&mut self, index: usize, mut slab: NonNull<Slab>) {
+        unsafe {
+            let slab_ref = slab.as_mut();
+            slab_ref.previous = None;
+            slab_ref.next = self.partial[index];
+            if let Some(mut next) = slab_ref.next {
+                next.as_mut().previous = Some(slab);
+            }
+        }
+        self.partial[index] = Some(slab);
+    }
+
+    fn unlink(&mut self, index: usize, slab: NonNull<Slab>) {
+        unsafe {
+            let slab = slab.as_ref();
+            match slab.previous {
+                Some(mut previous) => previous.as_mut().next = slab.next,
+                None => self.partial[index] = slab.next,
+            }
+            if let Some(mut next) = slab.next {
+                next.as_mut().previous = slab.previous;
+            }
+        }
+    }
+}
+
+impl HeapBackend for SlabHeap {
+    unsafe fn initialize(&mut self, bottom: *mut u8, size: usize) {
+        self.fallback.init(bottom, size)
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback.extend(by)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let Some(index) = size_index(layout) else {
+            return self.fallback.allocate_first_fit(layout).ok();
+        };
+
+        let mut slab = match self.partial[index] {
+            Some(slab) => slab,
+            None => self.new_slab(index)?,
+        };
+        let slab_ref = unsafe { slab.as_mut() };
+        // slabs on the partial list always have a free object
+        let object = slab_ref.free.unwrap();
+        slab_ref.free = unsafe { object.as_ref().next };
+        slab_ref.in_use += 1;
+        self.cached -= OBJECT_SIZES[index];
+        if slab_ref.free.is_none() {
+            self.unlink(index, slab);
+        }
+
+        Some(object.cast())
+    }
+
+    unsafe fn deallocate(&mut self, pointer: NonNull<u8>, layout: Layout) {
+        let Some(index) = size_index(layout) else {
+            return self.fallback.deallocate(pointer, layout);
+        };
+
+        let object_size = OBJECT_SIZES[index];
+        let slab_address = pointer.as_ptr() as usize & !(slab_size(object_size) - 1);
+        let mut slab = NonNull::new_unchecked(slab_address as *mut Slab);
+        let slab_ref = slab.as_mut();
+
+        // a full slab is back to having room
+        let was_full = slab_ref.free.is_none();
+        let object = pointer.cast::<Object>();
+        object.as_ptr().write(Object {
+            next: slab_ref.free,
+        });
+        slab_ref.free = Some(object);
+        slab_ref.in_use -= 1;
+        self.cached += object_size;
+        if was_full {
+            self.push(index, slab);
+        }
+
+        let is_only = self.partial[index] == Some(slab) && slab.as_ref().next.is_none();
+        if slab.as_ref().in_use == 0 && !is_only {
+            self.unlink(index, slab);
+            self.cached -= capacity(object_size) * object_size;
+            self.fallback
+                .deallocate(slab.cast(), slab_layout(object_size));
+        }
+    }
+
+    fn backing_layout(&self, layout: Layout) -> Layout {
+        size_index(layout).map_or(layout, |index| slab_layout(OBJECT_SIZES[index]))
+    }
+
+    fn size(&self) -> usize {
+        self.fallback.size()
+    }
+
+    fn used(&self) -> usize {
+        self.fallback.used() - self.cached
+    }
+}
+
+// The smallest object size that fits the layout, or `None` if it has to go to the fallback heap
+fn size_index(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+
+    OBJECT_SIZES
+        .iter()
+        .position(|&object_size| object_size >= size)
+}
+
+fn slab_size(object_size: usize) -> usize {
+    (object_size * MIN_OBJECTS).max(PAGE_SIZE)
+}
+
+fn slab_layout(object_size: usize) -> Layout {
+    let size = slab_size(object_size);
+
+    Layout::from_size_align(size, size).unwrap()
+}
+
+// Objects start at the first multiple of their size past the header
+fn first_object(object_size: usize) -> usize {
+    (size_of::<Slab>() + object_size - 1) / object_size * object_size
+}
+
+fn capacity(object_size: usize) -> usize {
+    (slab_size(object_size) - first_object(object_size)) / object_size
+}
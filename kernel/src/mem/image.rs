@@ -0,0 +1,118 @@
+use super::{
+    error::{Error, Result},
+    physical_to_virtual,
+};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, Mapper, Page, PageTableFlags, Translate},
+    PhysAddr, VirtAddr,
+};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+// Offsets into the ELF64 file header
+const PROGRAM_HEADER_OFFSET: usize = 0x20;
+const PROGRAM_HEADER_SIZE: usize = 0x36;
+const PROGRAM_HEADER_COUNT: usize = 0x38;
+// Offsets into an ELF64 program header
+const SEGMENT_TYPE: usize = 0x00;
+const SEGMENT_FLAGS: usize = 0x04;
+const SEGMENT_ADDRESS: usize = 0x10;
+const SEGMENT_SIZE: usize = 0x28;
+
+const LOAD: u32 = 1;
+const EXECUTABLE: u32 = 1;
+const WRITABLE: u32 = 2;
+
+/// Maps each segment of the kernel with only the access its ELF program header asks for, so
+/// text ends up read-execute, rodata read-only and data and bss read-write but not executable.
+///
+/// This function is unsafe because the caller must guarantee that the bootloader left the
+/// kernel's ELF file at `kernel_address` and loaded it `image_offset` bytes past its link
+/// address.
+pub unsafe fn protect(kernel_address: PhysAddr, image_offset: u64) -> Result<()> {
+    let file = physical_to_virtual(kernel_address).as_ptr::<u8>();
+    if read::<[u8; 4]>(file, 0) != ELF_MAGIC {
+        return Err(Error::KernelImage);
+    }
+    let program_headers = file.add(read::<u64>(file, PROGRAM_HEADER_OFFSET) as usize);
+    let header_size = read::<u16>(file, PROGRAM_HEADER_SIZE) as usize;
+    let header_count = read::<u16>(file, PROGRAM_HEADER_COUNT) as usize;
+
+    let mut mapper = super::mapper();
+    // the last page of the previous segment, which the next one may share
+    let mut previous: Option<(Page, u32)> = None;
+    for i in 0..header_count {
+        let header = program_headers.add(i * header_size);
+        let size = read::<u64>(header, SEGMENT_SIZE);
+        if read::<u32>(header, SEGMENT_TYPE) != LOAD || size == 0 {
+            continue;
+        }
+        let start = VirtAddr::new(read::<u64>(header, SEGMENT_ADDRESS) + image_offset);
+        let access = read::<u32>(header, SEGMENT_FLAGS);
+
+        let first = Page::containing_address(start);
+        let last = Page::containing_address(start + size - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            // a shared page needs what both segments need, even if that breaks W^X
+            let access = match previous {
+                Some((shared, previous_access)) if shared == page => access | previous_access,
+                _ => access,
+            };
+
+            let TranslateResult::Mapped { mut flags, .. } = mapper.translate(page.start_address())
+            else {
+                return Err(Error::NotMapped(page.start_address()));
+            };
+            flags.set(PageTableFlags::WRITABLE, access & WRITABLE != 0);
+            flags.set(PageTableFlags::NO_EXECUTE, access & EXECUTABLE == 0);
+            mapper.update_flags(page, flags)?.flush();
+        }
+        previous = Some((last, access));
+    }
+
+    Ok(())
+}
+
+unsafe fn read<T: Copy>(base: *const u8, offset: usize) -> T {
+    base.add(offset).cast::<T>().read_unaligned()
+}
+
+#[cfg(test)]
+fn flags(address: VirtAddr) -> PageTableFlags {
+    match super::mapper().translate(address) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{address:?} isn't mapped"),
+    }
+}
+
+#[test_case]
+fn text_is_read_execute_and_data_is_not_executable() {
+    static mut DATA: u64 = 0;
+
+    let text = flags(VirtAddr::new(flags as usize as u64));
+    assert!(!text.contains(PageTableFlags::WRITABLE));
+    assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+
+    let data = flags(VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(DATA) }));
+    assert!(data.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+    let heap = ::alloc::boxed::Box::new(0u64);
+    assert!(flags(VirtAddr::from_ptr(&*heap)).contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[cfg(test)]
+crate::should_panic! {
+    fn writing_to_text_faults() {
+        let text = flags as usize as *mut u8;
+        unsafe { text.write_volatile(0xcc) };
+    }
+}
+
+#[cfg(test)]
+crate::should_panic! {
+    fn executing_from_the_heap_faults() {
+        // a lone `ret`
+        let code = ::alloc::boxed::Box::new([0xc3u8]);
+        let function: fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+        function();
+    }
+}
@@ -1,25 +1,182 @@
 pub mod alloc;
+pub mod dma;
 mod error;
 pub mod heap;
+pub mod image;
+pub mod vmm;
 
+use alloc::BitmapFrameAllocator;
+use bootloader_api::info::MemoryRegions;
+use core::{
+    arch::asm,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
 pub use error::{
     Error as MemoryError, FrameError, PhysicalMemoryOffsetError, Result as MemoryResult,
 };
 use error::{Error, Result};
+use spin::{Mutex, MutexGuard, Once};
 use x86_64::{
-    registers::control::Cr3,
-    structures::paging::{OffsetPageTable, PageTable},
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags, Msr},
+    },
+    structures::paging::{
+        page_table, OffsetPageTable, PageSize, PageTable, PageTableFlags, Size1GiB, Size2MiB,
+    },
     PhysAddr, VirtAddr,
 };
 
-pub unsafe fn initialize(physical_memory_offset: Option<&u64>) -> Result<OffsetPageTable<'static>> {
+// A page's WRITE_THROUGH and NO_CACHE flags pick its memory type out of the page attribute table.
+// Entry 2, NO_CACHE alone, is uncached-minus out of reset and unused here, so it's made write
+// combining instead
+const PAGE_ATTRIBUTE_TABLE: u32 = 0x277;
+const WRITE_COMBINING_ENTRY: u64 = 2;
+const WRITE_COMBINING: u64 = 0x01;
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+static FRAME_ALLOCATOR: Once<Mutex<BitmapFrameAllocator>> = Once::new();
+
+/// Sets up the kernel's page table mapper and frame allocator, and turns on the no-execute page
+/// flag, write protection for the kernel's own accesses and write combining.
+///
+/// This function is unsafe because the caller must guarantee that the complete physical memory
+/// is mapped at `physical_memory_offset` and that every `USABLE` frame in `memory_regions` is
+/// really unused.
+pub unsafe fn initialize(
+    physical_memory_offset: Option<&u64>,
+    memory_regions: &'static MemoryRegions,
+) -> Result<()> {
     let physical_memory_offset = match physical_memory_offset {
         Some(offset) => VirtAddr::new(*offset),
         None => return Err(Error::PhysicalMemoryOffset(PhysicalMemoryOffsetError)),
     };
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    set_page_attribute(WRITE_COMBINING_ENTRY, WRITE_COMBINING);
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
     let level_4_table = active_level_4_table(physical_memory_offset);
+    MAPPER.call_once(|| Mutex::new(OffsetPageTable::new(level_4_table, physical_memory_offset)));
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(BitmapFrameAllocator::new(memory_regions)));
+
+    Ok(())
+}
+
+// Changes an entry of the page attribute table the way the SDM says memory types have to be
+// changed: with caching off and the caches and TLBs flushed before and after, so nothing cached
+// or translated under the entry's old type is left behind. Only this processor is running yet
+unsafe fn set_page_attribute(entry: u64, memory_type: u64) {
+    interrupts::without_interrupts(|| {
+        let cr0 = Cr0::read();
+        // no-fill cache mode
+        Cr0::write((cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH);
+        asm!("wbinvd", options(nostack, preserves_flags));
+        flush_tlb();
+
+        let mut page_attribute_table = Msr::new(PAGE_ATTRIBUTE_TABLE);
+        let shift = entry * 8;
+        let entries = page_attribute_table.read() & !(0xff << shift);
+        page_attribute_table.write(entries | memory_type << shift);
+
+        asm!("wbinvd", options(nostack, preserves_flags));
+        flush_tlb();
+        Cr0::write(cr0);
+    })
+}
+
+// Reloading CR3 keeps global pages cached, turning them off and on again flushes those too
+unsafe fn flush_tlb() {
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+        Cr4::write(cr4);
+    } else {
+        tlb::flush_all();
+    }
+}
+
+/// Locks the kernel's page tables.
+///
+/// Panics if called before `initialize`. When both are needed, lock this before the frame
+/// allocator.
+pub fn mapper() -> InterruptSafeGuard<OffsetPageTable<'static>> {
+    InterruptSafeGuard::lock(
+        MAPPER
+            .get()
+            .expect("page tables used before memory was initialized"),
+    )
+}
+
+/// Locks the physical frame allocator.
+///
+/// Panics if called before `initialize`.
+pub fn frame_allocator() -> InterruptSafeGuard<BitmapFrameAllocator> {
+    InterruptSafeGuard::lock(
+        FRAME_ALLOCATOR
+            .get()
+            .expect("frame allocator used before memory was initialized"),
+    )
+}
+
+/// Holds one of the memory locks with interrupts disabled, so an interrupt handler that
+/// allocates can't find the page tables or frame allocator taken when the page fault handler
+/// backs a new heap page for it.
+///
+/// Guards are expected to be dropped in the reverse order they were taken, the last one restores
+/// interrupts.
+pub struct InterruptSafeGuard<T: 'static> {
+    guard: ManuallyDrop<MutexGuard<'static, T>>,
+    interrupts_enabled: bool,
+}
 
-    Ok(OffsetPageTable::new(level_4_table, physical_memory_offset))
+impl<T> InterruptSafeGuard<T> {
+    fn lock(mutex: &'static Mutex<T>) -> Self {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        Self {
+            guard: ManuallyDrop::new(mutex.lock()),
+            interrupts_enabled,
+        }
+    }
+}
+
+impl<T> Deref for InterruptSafeGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for InterruptSafeGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for InterruptSafeGuard<T> {
+    fn drop(&mut self) {
+        // the lock has to be free before anything can interrupt
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Returns where the bootloader mapped the given physical address.
+///
+/// Panics if called before `initialize`.
+pub fn physical_to_virtual(address: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("physical memory offset used before memory was initialized");
+
+    *offset + address.as_u64()
 }
 
 pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
@@ -32,7 +189,8 @@ pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static
 }
 
 /// Translates the given virtual address to the mapped physical address, or
-/// `None` if the address is not mapped.
+/// an error if the address is not mapped. 2 MiB and 1 GiB pages are followed
+/// like any other mapping.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
@@ -49,19 +207,55 @@ pub unsafe fn translate_address(
         address.p2_index(),
         address.p1_index(),
     ];
-    let mut frame = level_4_table_frame;
+    // how much memory an entry maps at each level, if it maps a page rather
+    // than the next table
+    let page_sizes = [None, Some(Size1GiB::SIZE), Some(Size2MiB::SIZE), None];
+    let mut frame = level_4_table_frame.start_address();
 
     // traverse the multi-level page table
-    for index in table_indexes {
+    for (index, page_size) in table_indexes.into_iter().zip(page_sizes) {
         // convert the frame into a page table reference
-        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let virt = physical_memory_offset + frame.as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
         let table = unsafe { &*table_ptr };
 
         // read the page table entry and update `frame`
-        frame = table[index].frame()?;
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(Error::from(page_table::FrameError::FrameNotPresent));
+        }
+        frame = entry.addr();
+
+        // a huge page ends the walk early, the rest of the address is the
+        // offset into it
+        if let Some(page_size) = page_size {
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Ok(frame + address.as_u64() % page_size);
+            }
+        }
     }
 
     // calculate the physical address by adding the page offset
-    Ok(frame.start_address() + u64::from(address.page_offset()))
+    Ok(frame + u64::from(address.page_offset()))
+}
+
+#[test_case]
+fn translation_follows_huge_pages() {
+    let physical_memory_offset = physical_to_virtual(PhysAddr::zero());
+    let translate = |address| unsafe { translate_address(address, physical_memory_offset) };
+
+    // the bootloader maps physical memory with huge pages where it can
+    let physical = PhysAddr::new(0x20_1234);
+    assert_eq!(translate(physical_to_virtual(physical)).unwrap(), physical);
+
+    // the heap is mapped with normal pages
+    let value = ::alloc::boxed::Box::new(0u64);
+    let address = VirtAddr::from_ptr(&*value);
+    let physical = translate(address).unwrap();
+    assert_eq!(
+        physical_to_virtual(physical).as_ptr::<u64>(),
+        &*value as *const u64
+    );
+
+    assert!(translate(VirtAddr::new(0x_7fff_0000_0000)).is_err());
 }
@@ -0,0 +1,939 @@
+use super::{
+    active_level_4_table,
+    error::{Error, Result},
+    heap::{HEAP_BOTTOM, HEAP_MAX_SIZE},
+    physical_to_virtual,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    fmt, ptr,
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{
+        frame::PhysFrameRange,
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Where the virtual memory manager hands out ranges from, the lower half above the fixed
+/// mappings.
+pub const VMM_BOTTOM: u64 = 0x_1000_0000_0000;
+pub const VMM_TOP: u64 = 0x_8000_0000_0000;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+// Each level 4 entry covers 512 GiB
+const LEVEL_4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+// The heap grows into this a page at a time as the allocator needs it
+const HEAP: Region = Region {
+    start: VirtAddr::new_truncate(HEAP_BOTTOM as u64),
+    size: HEAP_MAX_SIZE as u64,
+    kind: RegionKind::Lazy(Protection::READ_WRITE),
+};
+
+// Keyed by start address
+static REGIONS: Mutex<BTreeMap<u64, Region>> = Mutex::new(BTreeMap::new());
+
+/// A range of virtual memory that's in use.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    fn contains(&self, address: VirtAddr) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    // The name of the stack whose guard page `address` is in
+    fn overflowed_stack(&self, address: VirtAddr) -> Option<&'static str> {
+        match self.kind {
+            RegionKind::Stack(name)
+                if self.contains(address) && address < self.start + PAGE_SIZE =>
+            {
+                Some(name)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RegionKind {
+    /// Set aside for something managed elsewhere, like the bootloader's mappings.
+    Reserved(&'static str),
+    /// Backed by frames from the frame allocator, which are freed again on unmap.
+    Anonymous(Protection),
+    /// Like `Anonymous`, but each page only gets its frame the first time it's touched.
+    Lazy(Protection),
+    /// A kernel stack, backed like read-write `Anonymous` memory except for its lowest page,
+    /// which is left unmapped so running off the end of the stack faults.
+    Stack(&'static str),
+    /// A window onto physical memory the region doesn't own, such as device registers.
+    Physical {
+        address: PhysAddr,
+        protection: Protection,
+        caching: Caching,
+    },
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (start, end) = (self.start.as_u64(), self.end().as_u64());
+
+        write!(f, "{start:#014x}..{end:#014x} {}", self.kind)
+    }
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reserved(name) => write!(f, "reserved for {name}"),
+            Self::Anonymous(protection) => write!(f, "anonymous {protection}"),
+            Self::Lazy(protection) => write!(f, "lazy {protection}"),
+            Self::Stack(name) => write!(f, "{name} stack"),
+            Self::Physical {
+                address,
+                protection,
+                caching,
+            } => write!(
+                f,
+                "physical {protection} {caching:?} from {:#x}",
+                address.as_u64()
+            ),
+        }
+    }
+}
+
+/// What a mapping can be used for. Everything is readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Protection {
+    pub const READ: Self = Self {
+        writable: false,
+        executable: false,
+    };
+    pub const READ_WRITE: Self = Self {
+        writable: true,
+        executable: false,
+    };
+    pub const READ_EXECUTE: Self = Self {
+        writable: false,
+        executable: true,
+    };
+
+    fn allows(self, access: Access) -> bool {
+        match access {
+            Access::Read => true,
+            Access::Write => self.writable,
+            Access::Execute => self.executable,
+        }
+    }
+
+    fn flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        flags.set(PageTableFlags::WRITABLE, self.writable);
+        flags.set(PageTableFlags::NO_EXECUTE, !self.executable);
+
+        flags
+    }
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let write = if self.writable { "-write" } else { "" };
+        let execute = if self.executable { "-execute" } else { "" };
+
+        write!(f, "read{write}{execute}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caching {
+    WriteBack,
+    WriteThrough,
+    /// For device registers, every access goes straight to the device.
+    Uncached,
+    /// Writes are buffered and sent on in bursts, for memory only a device reads, like DMA
+    /// buffers or framebuffers.
+    WriteCombining,
+}
+
+impl Caching {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            Self::WriteBack => PageTableFlags::empty(),
+            Self::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            Self::Uncached => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+            // see `mem::initialize`
+            Self::WriteCombining => PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+/// What a faulting access was trying to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Execute => "instruction fetch",
+        };
+
+        write!(f, "{description}")
+    }
+}
+
+/// A page fault demand paging couldn't do anything about.
+#[derive(Debug)]
+pub struct InvalidAccess {
+    pub address: VirtAddr,
+    pub access: Access,
+    /// The region the address is in, if any.
+    pub region: Option<Region>,
+    /// Set if the access was allowed but backing the page failed.
+    pub error: Option<Error>,
+}
+
+impl fmt::Display for InvalidAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:#x} ", self.access, self.address.as_u64())?;
+        match &self.region {
+            Some(region) => match region.overflowed_stack(self.address) {
+                Some(name) => write!(f, "overflowed the {name} stack")?,
+                None => write!(f, "in {region}")?,
+            },
+            None => write!(f, "outside any region")?,
+        }
+        if let Some(error) = &self.error {
+            write!(f, ": {error}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reserves everything already mapped when the kernel starts, so ranges handed out later can't
+/// run into the bootloader's mappings, and sets the heap's range up to be backed as it's touched.
+///
+/// Must be called after `mem::initialize`.
+pub fn initialize() -> Result<()> {
+    // the bootloader picks where its mappings go, so whole level 4 entries in use are set aside
+    let used_entries = {
+        let physical_memory_offset = physical_to_virtual(PhysAddr::zero());
+        let level_4_table = unsafe { active_level_4_table(physical_memory_offset) };
+        level_4_table
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.is_unused())
+            .map(|(i, _)| i as u64)
+            .collect::<Vec<_>>()
+    };
+
+    REGIONS.lock().insert(HEAP.start.as_u64(), HEAP);
+    for i in used_entries
+        .into_iter()
+        .filter(|&i| i * LEVEL_4_ENTRY_SIZE < VMM_TOP)
+    {
+        let start = VirtAddr::new(i * LEVEL_4_ENTRY_SIZE);
+        // the heap's own entry is already set aside
+        if !overlaps(&REGIONS.lock(), start, LEVEL_4_ENTRY_SIZE) {
+            reserve(start, LEVEL_4_ENTRY_SIZE, "boot mappings")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks `size` bytes from `start` as in use without mapping anything.
+pub fn reserve(start: VirtAddr, size: u64, name: &'static str) -> Result<()> {
+    let start = start.align_down(PAGE_SIZE);
+    let size = align_up(size);
+    let mut regions = REGIONS.lock();
+    if overlaps(&regions, start, size) {
+        return Err(Error::Overlap {
+            start,
+            end: start + size,
+        });
+    }
+
+    let kind = RegionKind::Reserved(name);
+    regions.insert(start.as_u64(), Region { start, size, kind });
+
+    Ok(())
+}
+
+/// Maps `size` bytes of fresh, zeroed memory somewhere free, returning where.
+pub fn map(size: u64, protection: Protection) -> Result<VirtAddr> {
+    let size = align_up(size);
+    let start = insert(size, RegionKind::Anonymous(protection), PAGE_SIZE, 0)?;
+
+    let result = {
+        let mut mapper = super::mapper();
+        let mut frame_allocator = super::frame_allocator();
+        map_anonymous(&mut mapper, &mut *frame_allocator, start, size, protection)
+    };
+    if let Err(error) = result {
+        REGIONS.lock().remove(&start.as_u64());
+        return Err(error);
+    }
+
+    Ok(start)
+}
+
+/// Maps a `size` byte stack with an unmapped guard page below it, returning its top. `name`
+/// identifies the stack when it overflows.
+pub fn map_stack(size: u64, name: &'static str) -> Result<VirtAddr> {
+    let size = align_up(size);
+    let start = insert(size + PAGE_SIZE, RegionKind::Stack(name), PAGE_SIZE, 0)?;
+    let bottom = start + PAGE_SIZE;
+
+    let result = {
+        let mut mapper = super::mapper();
+        let mut frame_allocator = super::frame_allocator();
+        map_anonymous(
+            &mut mapper,
+            &mut *frame_allocator,
+            bottom,
+            size,
+            Protection::READ_WRITE,
+        )
+    };
+    if let Err(error) = result {
+        REGIONS.lock().remove(&start.as_u64());
+        return Err(error);
+    }
+
+    Ok(bottom + size)
+}
+
+/// Sets aside `size` bytes somewhere free without backing them yet, returning where. Each page
+/// gets a fresh, zeroed frame from the page fault handler the first time it's touched.
+pub fn map_lazy(size: u64, protection: Protection) -> Result<VirtAddr> {
+    insert(align_up(size), RegionKind::Lazy(protection), PAGE_SIZE, 0)
+}
+
+/// Maps `size` bytes of physical memory starting at `address` somewhere free, returning the
+/// virtual address `address` ended up at.
+///
+/// Windows of 2 MiB or more are mapped with huge pages where the physical range allows it.
+pub fn map_physical(
+    address: PhysAddr,
+    size: u64,
+    protection: Protection,
+    caching: Caching,
+) -> Result<VirtAddr> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(address);
+    let offset = address - first_frame.start_address();
+    let size = align_up(offset + size);
+    let kind = RegionKind::Physical {
+        address: first_frame.start_address(),
+        protection,
+        caching,
+    };
+    // the region starts as far into the biggest page that fits as the physical range does, so
+    // the two line up on huge page boundaries
+    let alignment = [Size1GiB::SIZE, Size2MiB::SIZE]
+        .into_iter()
+        .filter(|&page_size| page_size != Size1GiB::SIZE || gigabyte_pages_supported())
+        .find(|&page_size| size >= page_size)
+        .unwrap_or(PAGE_SIZE);
+    let phase = first_frame.start_address().as_u64() % alignment;
+    let start = insert(size, kind, alignment, phase)?;
+
+    let flags = protection.flags() | caching.flags();
+    let result = {
+        let mut mapper = super::mapper();
+        let mut frame_allocator = super::frame_allocator();
+        map_frames(
+            &mut mapper,
+            &mut *frame_allocator,
+            start,
+            first_frame.start_address(),
+            size,
+            flags,
+        )
+    };
+    if let Err(error) = result {
+        unmap_pages(start, size, false);
+        REGIONS.lock().remove(&start.as_u64());
+        return Err(error);
+    }
+
+    Ok(start + offset)
+}
+
+/// Unmaps the region containing `address`, giving its frames back to the frame allocator if
+/// they came from there.
+pub fn unmap(address: VirtAddr) -> Result<()> {
+    let region = {
+        let mut regions = REGIONS.lock();
+        let region = find(&regions, address)?;
+        regions.remove(&region.start.as_u64());
+
+        region
+    };
+
+    let owns_frames = matches!(
+        region.kind,
+        RegionKind::Anonymous(_) | RegionKind::Lazy(_) | RegionKind::Stack(_)
+    );
+    unmap_pages(region.start, region.size, owns_frames);
+
+    Ok(())
+}
+
+/// Changes what the region containing `address` can be used for.
+pub fn protect(address: VirtAddr, protection: Protection) -> Result<()> {
+    let mut regions = REGIONS.lock();
+    let start = find(&regions, address)?.start;
+    let region = regions.get_mut(&start.as_u64()).unwrap();
+
+    let flags = match &mut region.kind {
+        RegionKind::Anonymous(current) | RegionKind::Lazy(current) => {
+            *current = protection;
+            protection.flags()
+        }
+        RegionKind::Physical {
+            protection: current,
+            caching,
+            ..
+        } => {
+            *current = protection;
+            protection.flags() | caching.flags()
+        }
+        RegionKind::Stack(_) => return Err(Error::StackProtection(address)),
+        RegionKind::Reserved(_) => unreachable!(),
+    };
+
+    let mut mapper = super::mapper();
+    let mut offset = 0;
+    while offset < region.size {
+        let address = region.start + offset;
+        // pages are stepped over by their own size, huge pages are updated as a whole
+        offset += match mapper.translate(address) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                ..
+            } => {
+                let page = Page::<Size4KiB>::containing_address(address);
+                unsafe { mapper.update_flags(page, flags)?.flush() };
+                Size4KiB::SIZE
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                let page = Page::<Size2MiB>::containing_address(address);
+                unsafe { mapper.update_flags(page, flags)?.flush() };
+                Size2MiB::SIZE
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size1GiB(_),
+                ..
+            } => {
+                let page = Page::<Size1GiB>::containing_address(address);
+                unsafe { mapper.update_flags(page, flags)?.flush() };
+                Size1GiB::SIZE
+            }
+            // lazy pages pick up the new flags when they're first touched
+            _ => PAGE_SIZE,
+        };
+    }
+
+    Ok(())
+}
+
+/// Gives the bootloader's mapping of `frames`, the one `physical_to_virtual` points into, the
+/// memory type `caching`. Memory mapped elsewhere as anything but write-back has to be mapped the
+/// same way there too, the processor doesn't support one piece of memory having two types.
+///
+/// Huge pages covering the frames are split up so nothing around them changes type.
+pub fn set_physical_mapping_caching(
+    frames: PhysFrameRange<Size4KiB>,
+    caching: Caching,
+) -> Result<()> {
+    let mut mapper = super::mapper();
+    let mut frame_allocator = super::frame_allocator();
+    for frame in frames {
+        let address = physical_to_virtual(frame.start_address());
+        loop {
+            match mapper.translate(address) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(_),
+                    flags,
+                    ..
+                } => {
+                    let types = PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+                    let page = Page::<Size4KiB>::containing_address(address);
+                    let flags = (flags - types) | caching.flags();
+                    unsafe { mapper.update_flags(page, flags)?.flush() };
+                    break;
+                }
+                TranslateResult::Mapped { .. } => split_huge_page(&mut *frame_allocator, address)?,
+                _ => return Err(Error::NotMapped(address)),
+            }
+        }
+
+        // lines cached under the old type mustn't be written back over the memory later
+        for line in (0..PAGE_SIZE).step_by(64) {
+            let pointer = (address + line).as_ptr::<u8>();
+            unsafe { asm!("clflush [{}]", in(reg) pointer, options(nostack, preserves_flags)) };
+        }
+    }
+
+    Ok(())
+}
+
+/// The name of the stack `address` is the guard page of, for fault handlers to tell a stack
+/// overflow apart from other bad accesses. Gives up if the region table is locked.
+pub fn overflowed_stack(address: VirtAddr) -> Option<&'static str> {
+    let regions = REGIONS.try_lock()?;
+    let (_, region) = regions.range(..=address.as_u64()).next_back()?;
+
+    region.overflowed_stack(address)
+}
+
+/// Backs the page at `address` if it's in a lazy region that allows `access`, for the page fault
+/// handler.
+///
+/// The fault may have come from code holding the region table, page tables or frame allocator,
+/// so they're only tried rather than waited on.
+pub fn handle_fault(address: VirtAddr, access: Access) -> core::result::Result<(), InvalidAccess> {
+    let invalid = |region, error| InvalidAccess {
+        address,
+        access,
+        region,
+        error,
+    };
+
+    // code holding the region table allocates, so growing into the heap mustn't need it
+    let region = match HEAP.contains(address) {
+        true => HEAP,
+        false => {
+            let Some(regions) = REGIONS.try_lock() else {
+                return Err(invalid(None, Some(Error::Locked)));
+            };
+            let region = regions
+                .range(..=address.as_u64())
+                .next_back()
+                .map(|(_, region)| *region)
+                .filter(|region| region.contains(address));
+            let Some(region) = region else {
+                return Err(invalid(None, None));
+            };
+
+            region
+        }
+    };
+    let RegionKind::Lazy(protection) = region.kind else {
+        return Err(invalid(Some(region), None));
+    };
+    if !protection.allows(access) {
+        return Err(invalid(Some(region), None));
+    }
+
+    let (Some(mapper), Some(frame_allocator)) = (super::MAPPER.get(), super::FRAME_ALLOCATOR.get())
+    else {
+        return Err(invalid(Some(region), Some(Error::Locked)));
+    };
+    let (Some(mut mapper), Some(mut frame_allocator)) =
+        (mapper.try_lock(), frame_allocator.try_lock())
+    else {
+        return Err(invalid(Some(region), Some(Error::Locked)));
+    };
+
+    let page = Page::<Size4KiB>::containing_address(address);
+    // a present page the access wasn't allowed on, the flags can't be out of date
+    if mapper.translate_page(page).is_ok() {
+        return Err(invalid(Some(region), None));
+    }
+    let Some(frame) = frame_allocator.allocate_frame() else {
+        return Err(invalid(Some(region), Some(Error::OutOfFrames)));
+    };
+    let frame_pointer = physical_to_virtual(frame.start_address()).as_mut_ptr::<u8>();
+    unsafe { ptr::write_bytes(frame_pointer, 0, PAGE_SIZE as usize) };
+
+    match unsafe { mapper.map_to(page, frame, protection.flags(), &mut *frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(error) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(invalid(Some(region), Some(Error::from(error))))
+        }
+    }
+}
+
+/// Returns every region in use, in address order.
+pub fn regions() -> Vec<Region> {
+    REGIONS.lock().values().copied().collect()
+}
+
+// Finds a free range starting `phase` bytes past a multiple of `alignment` and records it as
+// taken, leaving an unmapped guard page after every region so running off its end faults
+fn insert(size: u64, kind: RegionKind, alignment: u64, phase: u64) -> Result<VirtAddr> {
+    let mut regions = REGIONS.lock();
+    // the first suitable start at or after `address`
+    let place = |address: u64| address + (alignment + phase - address % alignment) % alignment;
+
+    let mut start = place(VMM_BOTTOM);
+    for region in regions.range(VMM_BOTTOM..).map(|(_, region)| region) {
+        if region.start.as_u64() >= start + size + PAGE_SIZE {
+            break;
+        }
+        start = start.max(place(region.end().as_u64() + PAGE_SIZE));
+    }
+    if start + size > VMM_TOP {
+        return Err(Error::OutOfAddressSpace(size));
+    }
+
+    let start = VirtAddr::new(start);
+    regions.insert(start.as_u64(), Region { start, size, kind });
+
+    Ok(start)
+}
+
+// The mapped region containing `address`, the heap's belongs to the allocator
+fn find(regions: &BTreeMap<u64, Region>, address: VirtAddr) -> Result<Region> {
+    regions
+        .range(..=address.as_u64())
+        .next_back()
+        .map(|(_, region)| *region)
+        .filter(|region| region.contains(address) && region.start != HEAP.start)
+        .filter(|region| !matches!(region.kind, RegionKind::Reserved(_)))
+        .ok_or(Error::NotMapped(address))
+}
+
+fn overlaps(regions: &BTreeMap<u64, Region>, start: VirtAddr, size: u64) -> bool {
+    let end = start + size;
+
+    regions
+        .range(..end.as_u64())
+        .next_back()
+        .map_or(false, |(_, region)| region.end() > start)
+}
+
+fn map_anonymous(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    start: VirtAddr,
+    size: u64,
+    protection: Protection,
+) -> Result<()> {
+    for i in 0..size / PAGE_SIZE {
+        let page = Page::containing_address(start + i * PAGE_SIZE);
+        let mapped = frame_allocator
+            .allocate_frame()
+            .ok_or(Error::OutOfFrames)
+            .and_then(|frame| {
+                // the frame is zeroed through the physical memory mapping, the page itself may
+                // not be writable
+                let frame_pointer = physical_to_virtual(frame.start_address()).as_mut_ptr::<u8>();
+                unsafe { ptr::write_bytes(frame_pointer, 0, PAGE_SIZE as usize) };
+
+                let flags = protection.flags();
+                match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                    Ok(flush) => {
+                        flush.flush();
+                        Ok(())
+                    }
+                    Err(error) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        Err(Error::from(error))
+                    }
+                }
+            });
+
+        if let Err(error) = mapped {
+            unmap_range(mapper, frame_allocator, start, i * PAGE_SIZE, true);
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+fn unmap_pages(start: VirtAddr, size: u64, owns_frames: bool) {
+    let mut mapper = super::mapper();
+    let mut frame_allocator = super::frame_allocator();
+
+    unmap_range(&mut mapper, &mut *frame_allocator, start, size, owns_frames);
+}
+
+// Maps the physical range with the biggest pages both sides are aligned for
+fn map_frames(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    start: VirtAddr,
+    physical: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<()> {
+    let gigabyte_pages = gigabyte_pages_supported();
+    let mut offset = 0;
+    while offset < size {
+        let (address, frame) = (start + offset, physical + offset);
+        let fits = |page_size| {
+            address.is_aligned(page_size)
+                && frame.is_aligned(page_size)
+                && size - offset >= page_size
+        };
+
+        offset += if gigabyte_pages && fits(Size1GiB::SIZE) {
+            map_page::<Size1GiB>(mapper, frame_allocator, address, frame, flags)?
+        } else if fits(Size2MiB::SIZE) {
+            map_page::<Size2MiB>(mapper, frame_allocator, address, frame, flags)?
+        } else {
+            map_page::<Size4KiB>(mapper, frame_allocator, address, frame, flags)?
+        };
+    }
+
+    Ok(())
+}
+
+// Returns the size of the page it mapped
+fn map_page<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    address: VirtAddr,
+    frame: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<u64>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(address);
+    let frame = PhysFrame::<S>::containing_address(frame);
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        // the error only has room for 4 KiB frames
+        Err(error) => {
+            return Err(Error::from(match error {
+                MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+                MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+                MapToError::PageAlreadyMapped(frame) => MapToError::PageAlreadyMapped(
+                    PhysFrame::containing_address(frame.start_address()),
+                ),
+            }))
+        }
+    }
+
+    Ok(S::SIZE)
+}
+
+// Skips pages that were never mapped, so this can also clean up after a mapping that failed
+// part way through. Only 4 KiB pages are ever backed by frames the region owns
+fn unmap_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    start: VirtAddr,
+    size: u64,
+    owns_frames: bool,
+) {
+    let mut offset = 0;
+    while offset < size {
+        let address = start + offset;
+        offset += match mapper.translate(address) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                let page = Page::<Size2MiB>::containing_address(address);
+                if let Ok((_, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                }
+                Size2MiB::SIZE
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size1GiB(_),
+                ..
+            } => {
+                let page = Page::<Size1GiB>::containing_address(address);
+                if let Ok((_, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                }
+                Size1GiB::SIZE
+            }
+            _ => {
+                let page = Page::<Size4KiB>::containing_address(address);
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    if owns_frames {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+                PAGE_SIZE
+            }
+        };
+    }
+}
+
+// Replaces the huge page `address` is in with a table of pages one size down, mapping the same
+// memory with the same flags
+fn split_huge_page(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    address: VirtAddr,
+) -> Result<()> {
+    let physical_memory_offset = physical_to_virtual(PhysAddr::zero());
+    let mut table = unsafe { active_level_4_table(physical_memory_offset) };
+    let levels = [
+        (address.p4_index(), LEVEL_4_ENTRY_SIZE),
+        (address.p3_index(), Size1GiB::SIZE),
+        (address.p2_index(), Size2MiB::SIZE),
+    ];
+
+    for (index, entry_size) in levels {
+        let entry = &mut table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let next = physical_to_virtual(entry.addr()).as_mut_ptr::<PageTable>();
+            table = unsafe { &mut *next };
+            continue;
+        }
+
+        let split = frame_allocator.allocate_frame().ok_or(Error::OutOfFrames)?;
+        let split_table =
+            unsafe { &mut *physical_to_virtual(split.start_address()).as_mut_ptr::<PageTable>() };
+        let page_size = entry_size / 512;
+        // the huge page flag is the PAT bit in a 4 KiB page's entry
+        let page_flags = match page_size {
+            PAGE_SIZE => flags - PageTableFlags::HUGE_PAGE,
+            _ => flags,
+        };
+        for (i, page) in split_table.iter_mut().enumerate() {
+            page.set_addr(entry.addr() + i as u64 * page_size, page_flags);
+        }
+        entry.set_frame(split, flags - PageTableFlags::HUGE_PAGE);
+        tlb::flush(address);
+
+        return Ok(());
+    }
+
+    Ok(())
+}
+
+// Bit 26 of the extended feature flags, QEMU's default CPU doesn't have it
+fn gigabyte_pages_supported() -> bool {
+    let features = unsafe { __cpuid(0x8000_0001) };
+
+    features.edx & (1 << 26) != 0
+}
+
+fn align_up(size: u64) -> u64 {
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[test_case]
+fn mapped_memory_is_usable_and_frames_come_back_on_unmap() {
+    let size = 3 * PAGE_SIZE;
+    let start = map(size, Protection::READ_WRITE).unwrap();
+    let memory =
+        unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr::<u8>(), size as usize) };
+    assert!(memory.iter().all(|&byte| byte == 0));
+    memory.fill(0xab);
+
+    // the next region starts past a guard page
+    let next = map(PAGE_SIZE, Protection::READ).unwrap();
+    assert!(next >= start + size + PAGE_SIZE);
+
+    // page tables stay around, only the frames behind the pages come back
+    let free_before = super::frame_allocator().statistics().free;
+    unmap(start).unwrap();
+    unmap(next).unwrap();
+    assert!(unmap(start).is_err());
+    assert_eq!(super::frame_allocator().statistics().free, free_before + 4);
+}
+
+#[test_case]
+fn large_physical_windows_use_huge_pages() {
+    let size = 4 * Size2MiB::SIZE;
+    let physical = PhysAddr::new(0x20_0000);
+    let start = map_physical(physical, size, Protection::READ, Caching::WriteBack).unwrap();
+    assert!(start.is_aligned(Size2MiB::SIZE));
+
+    match super::mapper().translate(start + 0x1234u64) {
+        TranslateResult::Mapped { frame, offset, .. } => {
+            assert_eq!(frame.size(), Size2MiB::SIZE);
+            assert_eq!(frame.start_address() + offset, physical + 0x1234u64);
+        }
+        _ => panic!("window isn't mapped"),
+    }
+
+    unmap(start).unwrap();
+    assert!(matches!(
+        super::mapper().translate(start),
+        TranslateResult::NotMapped
+    ));
+}
+
+#[test_case]
+fn lazy_pages_are_backed_on_first_touch() {
+    let start = map_lazy(3 * PAGE_SIZE, Protection::READ_WRITE).unwrap();
+    let page = |i| Page::<Size4KiB>::containing_address(start + i * PAGE_SIZE);
+
+    let first = start.as_ptr::<u64>();
+    let third = (start + 2 * PAGE_SIZE).as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(first.read_volatile(), 0);
+        third.write_volatile(0xabcd);
+        assert_eq!(third.read_volatile(), 0xabcd);
+    }
+    assert!(super::mapper().translate_page(page(0)).is_ok());
+    assert!(super::mapper().translate_page(page(1)).is_err());
+
+    // only the two touched pages took frames
+    let free_before = super::frame_allocator().statistics().free;
+    unmap(start).unwrap();
+    assert_eq!(super::frame_allocator().statistics().free, free_before + 2);
+}
+
+#[test_case]
+fn stacks_have_a_guard_page_below_them() {
+    let size = 4 * PAGE_SIZE;
+    let top = map_stack(size, "test").unwrap();
+    let bottom = top - size;
+    unsafe { bottom.as_mut_ptr::<u64>().write_volatile(1) };
+
+    let guard = bottom - 1u64;
+    assert!(super::mapper()
+        .translate_page(Page::<Size4KiB>::containing_address(guard))
+        .is_err());
+    assert_eq!(overflowed_stack(guard), Some("test"));
+    assert_eq!(overflowed_stack(bottom), None);
+
+    unmap(bottom).unwrap();
+    assert_eq!(overflowed_stack(guard), None);
+}
+
+#[cfg(test)]
+crate::should_panic! {
+    fn writing_to_a_read_only_lazy_page_panics() {
+        let start = map_lazy(PAGE_SIZE, Protection::READ).unwrap();
+        unsafe { start.as_mut_ptr::<u64>().write_volatile(1) };
+    }
+}
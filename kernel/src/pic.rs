@@ -0,0 +1,156 @@
+use x86_64::instructions::port::Port;
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub const CASCADE_IRQ: u8 = 2;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0b;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+struct Pic {
+    offset: u8,
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    unsafe fn end_of_interrupt(&mut self) {
+        self.command.write(END_OF_INTERRUPT);
+    }
+
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(OCW3_READ_ISR);
+        self.command.read()
+    }
+
+    unsafe fn mask(&mut self) -> u8 {
+        self.data.read()
+    }
+
+    unsafe fn set_mask(&mut self, mask: u8) {
+        self.data.write(mask)
+    }
+}
+
+/// The two legacy 8259 PICs, with the secondary cascaded through IRQ 2 of the primary.
+pub struct ChainedPics {
+    primary: Pic,
+    secondary: Pic,
+}
+
+impl ChainedPics {
+    /// Creates the PIC pair, to be remapped to the given vector offsets by `initialize`.
+    ///
+    /// This function is unsafe because the offsets must not overlap the CPU exception vectors or
+    /// each other.
+    pub const unsafe fn new(primary_offset: u8, secondary_offset: u8) -> Self {
+        Self {
+            primary: Pic {
+                offset: primary_offset,
+                command: Port::new(0x20),
+                data: Port::new(0x21),
+            },
+            secondary: Pic {
+                offset: secondary_offset,
+                command: Port::new(0xa0),
+                data: Port::new(0xa1),
+            },
+        }
+    }
+
+    /// Remaps both PICs to their vector offsets and masks every IRQ except the cascade.
+    pub fn initialize(&mut self) {
+        // Port 0x80 is unused, writing to it gives the PICs time to react on older hardware
+        let mut wait_port: Port<u8> = Port::new(0x80);
+        let mut wait = || unsafe { wait_port.write(0) };
+
+        unsafe {
+            self.primary.command.write(ICW1_INIT | ICW1_ICW4);
+            wait();
+            self.secondary.command.write(ICW1_INIT | ICW1_ICW4);
+            wait();
+
+            self.primary.data.write(self.primary.offset);
+            wait();
+            self.secondary.data.write(self.secondary.offset);
+            wait();
+
+            // tell the primary there's a secondary on IRQ 2, and the secondary its cascade identity
+            self.primary.data.write(1 << CASCADE_IRQ);
+            wait();
+            self.secondary.data.write(CASCADE_IRQ);
+            wait();
+
+            self.primary.data.write(ICW4_8086);
+            wait();
+            self.secondary.data.write(ICW4_8086);
+            wait();
+
+            self.primary.set_mask(!(1 << CASCADE_IRQ));
+            self.secondary.set_mask(0xff);
+        }
+    }
+
+    /// Masks every IRQ on both PICs, for when another interrupt controller takes over.
+    pub fn disable(&mut self) {
+        unsafe {
+            self.primary.set_mask(0xff);
+            self.secondary.set_mask(0xff);
+        }
+    }
+
+    pub fn mask(&mut self, irq: u8) {
+        let (pic, bit) = self.pic_for(irq);
+        unsafe {
+            let mask = pic.mask();
+            pic.set_mask(mask | 1 << bit)
+        }
+    }
+
+    pub fn unmask(&mut self, irq: u8) {
+        let (pic, bit) = self.pic_for(irq);
+        unsafe {
+            let mask = pic.mask();
+            pic.set_mask(mask & !(1 << bit))
+        }
+    }
+
+    /// Checks whether an IRQ is spurious, acknowledging the primary if a spurious IRQ 15 made it
+    /// raise the cascade line.
+    ///
+    /// IRQs 7 and 15 are raised when a request goes away before the CPU acknowledges it. A real
+    /// one sets its bit in the in-service register, a spurious one doesn't and must not get an
+    /// end of interrupt from the PIC that raised it.
+    pub fn is_spurious(&mut self, irq: u8) -> bool {
+        unsafe {
+            match irq {
+                7 => self.primary.in_service() & 0x80 == 0,
+                15 if self.secondary.in_service() & 0x80 == 0 => {
+                    self.primary.end_of_interrupt();
+                    true
+                }
+                _ => false,
+            }
+        }
+    }
+
+    pub fn end_of_interrupt(&mut self, irq: u8) {
+        unsafe {
+            if irq >= 8 {
+                self.secondary.end_of_interrupt();
+            }
+            self.primary.end_of_interrupt();
+        }
+    }
+
+    fn pic_for(&mut self, irq: u8) -> (&mut Pic, u8) {
+        match irq {
+            0..=7 => (&mut self.primary, irq),
+            8..=15 => (&mut self.secondary, irq - 8),
+            _ => panic!("IRQ {irq} is out of range for the 8259 PIC"),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A fixed capacity, lock-free, single producer single consumer queue.
+///
+/// Meant for handing data from an interrupt handler to the rest of the kernel. Only one context
+/// may push and only one context may pop, but the two can run concurrently, or interrupt each
+/// other, without any locking.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    buffer: UnsafeCell<[MaybeUninit<T>; N]>,
+    // both indices only ever increase, wrapping around, and are reduced modulo N on access
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// SAFETY: the producer only writes slots the consumer has released and vice versa, the atomic
+// indices order those accesses
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new(unsafe { MaybeUninit::uninit().assume_init() }),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends a value, handing it back if the queue is full. Producer side only.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+
+        unsafe { (*self.buffer.get())[tail % N].write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Removes the oldest value. Consumer side only.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { (*self.buffer.get())[head % N].assume_init() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+}
+
+#[test_case]
+fn ring_buffer_is_fifo_and_bounded() {
+    let queue = RingBuffer::<u8, 2>::new();
+    assert_eq!(queue.push(1), Ok(()));
+    assert_eq!(queue.push(2), Ok(()));
+    assert_eq!(queue.push(3), Err(3));
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(queue.push(3), Ok(()));
+    assert_eq!(queue.pop(), Some(2));
+    assert_eq!(queue.pop(), Some(3));
+    assert_eq!(queue.pop(), None);
+}
@@ -0,0 +1,150 @@
+use core::str;
+use spin::Once;
+
+const BLOCK_SIZE: usize = 512;
+
+static RAMDISK: Once<Ramdisk> = Once::new();
+
+/// Picks up the ramdisk the bootloader loaded, if it was given one.
+///
+/// This function is unsafe because the caller must guarantee that `address` and `length`
+/// describe memory that stays mapped and unmodified for the rest of the kernel's life, which is
+/// the case for what the bootloader reports in `BootInfo`.
+pub unsafe fn initialize(address: Option<u64>, length: u64) {
+    if let Some(address) = address {
+        let data = core::slice::from_raw_parts(address as *const u8, length as usize);
+        RAMDISK.call_once(|| Ramdisk::new(data));
+    }
+}
+
+/// Returns the ramdisk, or `None` if the kernel was booted without one.
+pub fn get() -> Option<&'static Ramdisk> {
+    RAMDISK.get()
+}
+
+/// Looks up a file on the ramdisk by its path, relative to the packed directory.
+pub fn file(path: &str) -> Option<&'static [u8]> {
+    get()?.file(path)
+}
+
+/// A read-only ustar archive, as packed by `build.rs`.
+pub struct Ramdisk {
+    data: &'static [u8],
+}
+
+impl Ramdisk {
+    pub fn new(data: &'static [u8]) -> Self {
+        Self { data }
+    }
+
+    /// The whole archive, headers included.
+    pub fn bytes(&self) -> &'static [u8] {
+        self.data
+    }
+
+    pub fn files(&self) -> Files {
+        Files {
+            data: self.data,
+            offset: 0,
+        }
+    }
+
+    pub fn file(&self, path: &str) -> Option<&'static [u8]> {
+        self.files()
+            .find(|file| file.has_path(path))
+            .map(|file| file.data)
+    }
+}
+
+/// A regular file in the archive.
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    // ustar splits long paths in two, joined by a slash
+    prefix: &'static str,
+    name: &'static str,
+    pub data: &'static [u8],
+}
+
+impl File {
+    pub fn has_path(&self, path: &str) -> bool {
+        match self.prefix {
+            "" => path == self.name,
+            prefix => path
+                .strip_prefix(prefix)
+                .and_then(|path| path.strip_prefix('/'))
+                .map_or(false, |name| name == self.name),
+        }
+    }
+
+    /// The file's name, the last part of its path.
+    pub fn name(&self) -> &'static str {
+        self.name.rsplit('/').next().unwrap_or(self.name)
+    }
+}
+
+/// Walks the files in an archive. Directories and other special entries are skipped, and walking
+/// stops at the end of the archive or at the first header that doesn't make sense.
+pub struct Files {
+    data: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Files {
+    type Item = File;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = self.data.get(self.offset..self.offset + BLOCK_SIZE)?;
+            // the archive ends with empty blocks
+            if header.iter().all(|&byte| byte == 0) || &header[257..262] != b"ustar" {
+                return None;
+            }
+
+            let size = parse_octal(&header[124..136])?;
+            let start = self.offset + BLOCK_SIZE;
+            let data = self.data.get(start..start + size)?;
+            self.offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+
+            // regular files, old archivers leave the type empty
+            if matches!(header[156], b'0' | 0) {
+                return Some(File {
+                    prefix: parse_string(&header[345..500])?,
+                    name: parse_string(&header[..100])?,
+                    data,
+                });
+            }
+        }
+    }
+}
+
+// Header strings are NUL padded, unless they fill their whole field
+fn parse_string(field: &'static [u8]) -> Option<&'static str> {
+    let length = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+
+    str::from_utf8(&field[..length]).ok()
+}
+
+// Header numbers are octal text, padded with NULs or spaces
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = field
+        .iter()
+        .copied()
+        .skip_while(|&byte| byte == b' ')
+        .take_while(|&byte| byte != 0 && byte != b' ');
+
+    digits.fold(Some(0), |value, digit| match digit {
+        b'0'..=b'7' => Some(value? * 8 + (digit - b'0') as usize),
+        _ => None,
+    })
+}
+
+#[test_case]
+fn ramdisk_holds_the_terminal_font() {
+    let font = file(crate::terminal::FONT_PATH).expect("font missing from the ramdisk");
+
+    assert!(!font.is_empty());
+    assert!(file("fonts/does-not-exist.ttf").is_none());
+}
@@ -0,0 +1,255 @@
+use crate::{idt, queue::RingBuffer};
+use core::fmt::{self, Write};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::{
+    interrupts,
+    port::{Port, PortReadOnly, PortWriteOnly},
+};
+
+pub const COM1: u16 = 0x3f8;
+const COM1_IRQ: u8 = 4;
+const BUFFER_SIZE: usize = 1024;
+// How many bytes the transmit FIFO takes once it has signalled it's empty
+const FIFO_SIZE: usize = 16;
+
+// Filled by the interrupt handler and drained by `read_byte`
+static RECEIVED: RingBuffer<u8, BUFFER_SIZE> = RingBuffer::new();
+
+lazy_static! {
+    static ref SERIAL: Mutex<SerialPort> = {
+        let mut port = unsafe { SerialPort::new(COM1) };
+        port.initialize();
+
+        Mutex::new(port)
+    };
+}
+
+#[doc(hidden)]
+pub fn print(args: fmt::Arguments) {
+    // UNWRAP: writing to a serial port can't fail, it just blocks until the transmitter is ready
+    interrupts::without_interrupts(|| SERIAL.lock().write_fmt(args).unwrap());
+}
+
+/// Switches between polling the port and driving it from IRQ 4.
+///
+/// Interrupt mode must only be turned on once the IDT is loaded, and should be turned off again
+/// before interrupts are disabled for good, otherwise anything still buffered is never sent.
+pub fn set_mode(mode: Mode) {
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL.lock();
+        if serial.mode == mode {
+            return;
+        }
+
+        match mode {
+            Mode::Polled => {
+                idt::unregister_irq(COM1_IRQ);
+                serial.set_mode(mode);
+            }
+            Mode::Interrupt => {
+                serial.set_mode(mode);
+                idt::register_irq(COM1_IRQ, interrupt_handler);
+            }
+        }
+    });
+}
+
+/// Sends everything still buffered for transmission, waiting for the port if needed.
+pub fn flush() {
+    interrupts::without_interrupts(|| SERIAL.lock().flush());
+}
+
+/// Takes the oldest byte received on the port, if there is one.
+pub fn read_byte() -> Option<u8> {
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL.lock();
+        match serial.mode {
+            Mode::Polled => serial.receive(),
+            Mode::Interrupt => RECEIVED.pop(),
+        }
+    })
+}
+
+// Writers disable interrupts while they hold the lock, so this can't deadlock against them
+fn interrupt_handler() {
+    SERIAL.lock().handle_interrupt();
+}
+
+/// Releases the serial lock regardless of who is holding it, and goes back to polling since no
+/// more interrupts are coming to drain the transmit buffer.
+///
+/// This is only meant for the panic handler, where the holder will never get to run again.
+pub unsafe fn force_unlock() {
+    if SERIAL.is_locked() {
+        SERIAL.force_unlock();
+    }
+    SERIAL.lock().set_mode(Mode::Polled);
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Every byte is sent by waiting on the transmitter, and reads check the port directly.
+    Polled,
+    /// Bytes are queued and sent from the transmitter empty interrupt, and received ones are
+    /// buffered as they arrive.
+    Interrupt,
+}
+
+/// A 16550 UART.
+pub struct SerialPort {
+    data: Port<u8>,
+    interrupt_enable: PortWriteOnly<u8>,
+    fifo_control: PortWriteOnly<u8>,
+    interrupt_identification: PortReadOnly<u8>,
+    line_control: PortWriteOnly<u8>,
+    modem_control: PortWriteOnly<u8>,
+    line_status: PortReadOnly<u8>,
+    mode: Mode,
+    transmit_buffer: RingBuffer<u8, BUFFER_SIZE>,
+}
+
+impl SerialPort {
+    /// Creates a serial port at the given I/O base address.
+    ///
+    /// This function is unsafe because the caller must guarantee that a UART actually lives at
+    /// `base`, and that nothing else is driving it.
+    pub const unsafe fn new(base: u16) -> Self {
+        Self {
+            data: Port::new(base),
+            interrupt_enable: PortWriteOnly::new(base + 1),
+            fifo_control: PortWriteOnly::new(base + 2),
+            interrupt_identification: PortReadOnly::new(base + 2),
+            line_control: PortWriteOnly::new(base + 3),
+            modem_control: PortWriteOnly::new(base + 4),
+            line_status: PortReadOnly::new(base + 5),
+            mode: Mode::Polled,
+            transmit_buffer: RingBuffer::new(),
+        }
+    }
+
+    /// Configures the port for 38400 baud, 8 data bits, no parity and one stop bit.
+    pub fn initialize(&mut self) {
+        unsafe {
+            self.interrupt_enable.write(0x00);
+
+            // set the baud rate divisor (115200 / 3) while DLAB is set
+            self.line_control.write(0x80);
+            self.data.write(0x03);
+            self.interrupt_enable.write(0x00);
+
+            self.line_control.write(0x03);
+            self.fifo_control.write(0xc7);
+            self.modem_control.write(0x0b);
+        }
+    }
+
+    /// Enables or disables the port's interrupts. Routing IRQ 4 to `handle_interrupt` is up to
+    /// the caller.
+    pub fn set_mode(&mut self, mode: Mode) {
+        match mode {
+            Mode::Polled => {
+                unsafe { self.interrupt_enable.write(0x00) };
+                self.flush();
+            }
+            // received data available and transmitter empty
+            Mode::Interrupt => unsafe { self.interrupt_enable.write(0x03) },
+        }
+        self.mode = mode;
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        if self.mode == Mode::Polled {
+            return self.send_now(byte);
+        }
+
+        // with the buffer full there's no choice but to wait on the port ourselves
+        if let Err(byte) = self.transmit_buffer.push(byte) {
+            self.flush();
+            self.send_now(byte);
+        }
+        // the transmitter only interrupts when it runs dry, so an idle one needs a first push
+        if self.transmit_empty() {
+            self.fill_fifo();
+        }
+    }
+
+    /// Sends everything buffered for transmission, blocking until it's all with the port.
+    pub fn flush(&mut self) {
+        while let Some(byte) = self.transmit_buffer.pop() {
+            self.send_now(byte);
+        }
+    }
+
+    /// Reads a byte straight from the port, if one has arrived.
+    pub fn receive(&mut self) -> Option<u8> {
+        match self.data_ready() {
+            true => Some(unsafe { self.data.read() }),
+            false => None,
+        }
+    }
+
+    /// Moves received bytes into the receive buffer and queued bytes into the transmitter.
+    pub fn handle_interrupt(&mut self) {
+        // reading the identification register acknowledges a transmitter empty interrupt
+        unsafe { self.interrupt_identification.read() };
+
+        while let Some(byte) = self.receive() {
+            // bytes are dropped once the buffer is full, like the UART itself would on overrun
+            let _ = RECEIVED.push(byte);
+        }
+        if self.transmit_empty() {
+            self.fill_fifo();
+        }
+    }
+
+    fn fill_fifo(&mut self) {
+        for _ in 0..FIFO_SIZE {
+            match self.transmit_buffer.pop() {
+                Some(byte) => unsafe { self.data.write(byte) },
+                None => break,
+            }
+        }
+    }
+
+    fn send_now(&mut self, byte: u8) {
+        while !self.transmit_empty() {
+            core::hint::spin_loop();
+        }
+
+        unsafe { self.data.write(byte) }
+    }
+
+    fn transmit_empty(&mut self) -> bool {
+        unsafe { self.line_status.read() & 0x20 != 0 }
+    }
+
+    fn data_ready(&mut self) -> bool {
+        unsafe { self.line_status.read() & 0x01 != 0 }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, data: &str) -> fmt::Result {
+        data.bytes().for_each(|byte| match byte {
+            b'\n' => {
+                self.send(b'\r');
+                self.send(b'\n');
+            }
+            byte => self.send(byte),
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+use crate::graphics::Color;
+
+const ESCAPE: char = '\x1b';
+const MAX_PARAMS: usize = 16;
+
+/// What the terminal should do after a character has gone through the parser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(char),
+    /// A C0 control character, like a newline or a carriage return.
+    Execute(char),
+    SaveCursor,
+    RestoreCursor,
+    Reset,
+    Csi(Csi),
+}
+
+/// A complete control sequence: `ESC [`, parameters separated by semicolons, and a final
+/// character naming the function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    count: usize,
+    /// Set for sequences using a private marker, like `ESC [ ? 25 l`.
+    pub private: bool,
+    pub function: char,
+}
+
+impl Csi {
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.count.min(MAX_PARAMS)]
+    }
+
+    /// Returns a parameter, or `default` if it was left out or zero.
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&param) if param != 0 => param,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// A VT100 style escape sequence parser, fed one character at a time.
+///
+/// Sequences may be split across any number of writes, the parser keeps its state in between.
+pub struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    count: usize,
+    private: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            count: 0,
+            private: false,
+        }
+    }
+
+    pub fn advance(&mut self, char: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match char {
+                ESCAPE => {
+                    self.state = State::Escape;
+                    None
+                }
+                _ if char.is_control() => Some(Action::Execute(char)),
+                _ => Some(Action::Print(char)),
+            },
+            State::Escape => {
+                self.state = State::Ground;
+                match char {
+                    '[' => {
+                        *self = Self::new();
+                        self.state = State::Csi;
+                        None
+                    }
+                    ESCAPE => {
+                        self.state = State::Escape;
+                        None
+                    }
+                    '7' => Some(Action::SaveCursor),
+                    '8' => Some(Action::RestoreCursor),
+                    'c' => Some(Action::Reset),
+                    // everything else we don't support is dropped along with its introducer
+                    _ => None,
+                }
+            }
+            State::Csi => match char {
+                '0'..='9' => {
+                    self.count = self.count.max(1);
+                    // parameters past the limit are ignored
+                    if let Some(param) = self.params.get_mut(self.count - 1) {
+                        let digit = char as u16 - '0' as u16;
+                        *param = param.saturating_mul(10).saturating_add(digit);
+                    }
+                    None
+                }
+                ';' | ':' => {
+                    self.count = (self.count.max(1) + 1).min(MAX_PARAMS + 1);
+                    None
+                }
+                '<'..='?' => {
+                    self.private = true;
+                    None
+                }
+                ESCAPE => {
+                    self.state = State::Escape;
+                    None
+                }
+                // intermediate bytes, none of the sequences we handle use them
+                '\x20'..='\x2f' => None,
+                '\x40'..='\x7e' => {
+                    self.state = State::Ground;
+                    Some(Action::Csi(Csi {
+                        params: self.params,
+                        count: self.count,
+                        private: self.private,
+                        function: char,
+                    }))
+                }
+                // controls still take effect in the middle of a sequence
+                _ if char.is_control() => Some(Action::Execute(char)),
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// The colors and attributes characters are currently drawn with.
+#[derive(Clone, Copy)]
+pub struct Style {
+    pub foreground: Color,
+    pub background: Color,
+    pub bold: bool,
+    pub inverse: bool,
+}
+
+impl Style {
+    pub fn new(foreground: Color, background: Color) -> Self {
+        Self {
+            foreground,
+            background,
+            bold: false,
+            inverse: false,
+        }
+    }
+
+    /// The foreground and background to actually draw with, after inversion.
+    pub fn colors(&self) -> (Color, Color) {
+        match self.inverse {
+            false => (self.foreground, self.background),
+            true => (self.background, self.foreground),
+        }
+    }
+
+    /// Applies the parameters of an SGR (`ESC [ ... m`) sequence, `default` is what resets go
+    /// back to.
+    pub fn select_graphic_rendition(&mut self, params: &[u16], default: Style) {
+        if params.is_empty() {
+            *self = default;
+            return;
+        }
+
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = default,
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.inverse = true,
+                27 => self.inverse = false,
+                30..=37 => self.foreground = palette(param as u8 - 30),
+                90..=97 => self.foreground = palette(param as u8 - 90 + 8),
+                40..=47 => self.background = palette(param as u8 - 40),
+                100..=107 => self.background = palette(param as u8 - 100 + 8),
+                38 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.foreground = color;
+                    }
+                }
+                48 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.background = color;
+                    }
+                }
+                39 => self.foreground = default.foreground,
+                49 => self.background = default.background,
+                _ => {}
+            }
+        }
+    }
+}
+
+// The `5;index` and `2;red;green;blue` forms following a 38 or 48
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(palette(params.next()? as u8)),
+        2 => {
+            let red = params.next()? as u8;
+            let green = params.next()? as u8;
+            let blue = params.next()? as u8;
+
+            Some(rgb(red, green, blue))
+        }
+        _ => None,
+    }
+}
+
+/// Looks up a color in the xterm 256 color palette.
+pub fn palette(index: u8) -> Color {
+    const STANDARD: [u32; 16] = [
+        0x000000, 0xcd0000, 0x00cd00, 0xcdcd00, 0x0000ee, 0xcd00cd, 0x00cdcd, 0xe5e5e5, 0x7f7f7f,
+        0xff0000, 0x00ff00, 0xffff00, 0x5c5cff, 0xff00ff, 0x00ffff, 0xffffff,
+    ];
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    match index {
+        0..=15 => Color::Rgb(STANDARD[index as usize] << 8),
+        16..=231 => {
+            let index = index - 16;
+            rgb(
+                CUBE_LEVELS[index as usize / 36],
+                CUBE_LEVELS[index as usize / 6 % 6],
+                CUBE_LEVELS[index as usize % 6],
+            )
+        }
+        _ => {
+            let level = 8 + (index - 232) * 10;
+            rgb(level, level, level)
+        }
+    }
+}
+
+fn rgb(red: u8, green: u8, blue: u8) -> Color {
+    Color::Rgb((red as u32) << 24 | (green as u32) << 16 | (blue as u32) << 8)
+}
+
+#[test_case]
+fn parser_splits_out_control_sequences() {
+    let mut parser = Parser::new();
+    let actions = "a\x1b[1;38;5;196mb"
+        .chars()
+        .filter_map(|char| parser.advance(char))
+        .collect::<alloc::vec::Vec<_>>();
+
+    assert_eq!(actions.len(), 3);
+    assert_eq!(actions[0], Action::Print('a'));
+    match actions[1] {
+        Action::Csi(csi) => {
+            assert_eq!(csi.params(), &[1, 38, 5, 196]);
+            assert_eq!(csi.function, 'm');
+            assert_eq!(csi.param(4, 1), 1);
+        }
+        action => panic!("expected a control sequence, got {action:?}"),
+    }
+    assert_eq!(actions[2], Action::Print('b'));
+}
+
+#[test_case]
+fn sgr_sets_and_resets_colors() {
+    let default = Style::new(Color::White, Color::Black);
+    let mut style = default;
+
+    style.select_graphic_rendition(&[1, 31, 48, 2, 1, 2, 3], default);
+    assert!(style.bold);
+    assert_eq!(style.foreground.as_rgb(), 0xcd000000);
+    assert_eq!(style.background.as_rgb(), 0x01020300);
+
+    style.select_graphic_rendition(&[0], default);
+    assert!(!style.bold);
+    assert_eq!(style.foreground.as_rgb(), Color::White.as_rgb());
+}
@@ -0,0 +1,50 @@
+use alloc::{
+    boxed::Box,
+    collections::{LinkedList, VecDeque},
+    string::String,
+};
+
+pub(super) struct TerminalBackBuffer {
+    pub history: Capped<LinkedList<Box<str>>>,
+    pub buffer: Capped<VecDeque<Box<str>>>,
+}
+
+impl TerminalBackBuffer {
+    pub fn new(history_capacity: usize, buffer_capacity: usize) -> Self {
+        Self {
+            history: (history_capacity, LinkedList::new()),
+            buffer: (buffer_capacity, VecDeque::new()),
+        }
+    }
+
+    pub fn push_command(&mut self, prompt: String, command: String) {
+        self.push_line(format!("{prompt}{command}").into_boxed_str());
+
+        // Empty lines aren't worth recalling
+        if command.is_empty() {
+            return;
+        }
+
+        // Remove a command from history if it's full
+        if self.history.1.len() == self.history.0 {
+            self.history.1.pop_front();
+        }
+        self.history.1.push_back(command.into_boxed_str())
+    }
+
+    pub fn push_line(&mut self, line: Box<str>) {
+        // Remove a line from the back of the buffer if it's full
+        if self.buffer.1.len() == self.buffer.0 {
+            self.buffer.1.pop_front();
+        }
+        self.buffer.1.push_back(line);
+    }
+}
+
+impl Default for TerminalBackBuffer {
+    fn default() -> Self {
+        Self::new(250, 1000)
+    }
+}
+
+pub(super) type Capped<T> = (usize, T);
@@ -0,0 +1,111 @@
+use super::FONT_PATH;
+use crate::{
+    graphics::{Color, Font, GraphicsDevice, Pixel, PixelMap},
+    ramdisk,
+};
+use alloc::{collections::BTreeMap, rc::Rc};
+use core::cell::RefCell;
+
+pub struct TerminalBackend<'a> {
+    pub(super) font: Font<'a>,
+    render_cache: Rc<RefCell<BTreeMap<char, PixelMap>>>,
+    device: Rc<RefCell<dyn GraphicsDevice>>,
+}
+
+impl<'a> TerminalBackend<'a> {
+    pub fn new(device: Rc<RefCell<dyn GraphicsDevice>>, font_size: usize) -> Self {
+        // let background = Color::Black;
+        // let foreground = Color::White;
+
+        // let mut device_ref = device.borrow_mut();
+        // let cursor_position = Point { x: 0, y: 0 };
+        // let dimensions = Point {
+        //     x: device_ref.width(),
+        //     y: device_ref.height(),
+        // };
+
+        // device_ref.fill(background);
+        // drop(device_ref);
+
+        let font_data = ramdisk::file(FONT_PATH)
+            .unwrap_or_else(|| panic!("terminal font {FONT_PATH} is missing from the ramdisk"));
+        let font = Font::new(font_data, font_size).unwrap();
+        let render_cache = Rc::new(RefCell::new(BTreeMap::new()));
+
+        Self {
+            font,
+            render_cache,
+            device,
+        }
+    }
+
+    pub fn update_font_size(&mut self, font_size: usize) {
+        self.render_cache.borrow_mut().clear();
+        self.font.update_height(font_size);
+    }
+
+    pub fn width(&self) -> usize {
+        self.device.borrow().width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.device.borrow().height()
+    }
+
+    pub fn clear(&self, color: Color) {
+        self.device.borrow_mut().fill(color);
+    }
+
+    pub fn fill_rect(&self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        self.device
+            .borrow_mut()
+            .fill_rect(x, y, width, height, color);
+    }
+
+    /// Moves everything on screen up by `rows` pixel rows, filling the bottom with `color`.
+    pub fn scroll_up(&self, rows: usize, color: Color) {
+        self.device.borrow_mut().scroll_up(rows, color);
+    }
+
+    // writes a character, blending its coverage from the background into the foreground color
+    pub fn write_character(
+        &self,
+        pixel_map: &PixelMap,
+        x_offset: i32,
+        y_offset: i32,
+        foreground: Color,
+        background: Color,
+    ) {
+        let mut device_ref = self.device.borrow_mut();
+        let (width, height) = (device_ref.width() as i32, device_ref.height() as i32);
+        pixel_map.iter().for_each(|Pixel { position, color }| {
+            let x = position.x + x_offset;
+            let y = position.y + y_offset;
+
+            // glyphs can hang off the edges of the screen, those parts are just not drawn
+            if (0..width).contains(&x) && (0..height).contains(&y) {
+                // glyphs are rasterized as grayscale, any channel holds the coverage
+                let coverage = (*color >> 24) as u8;
+                let color = background.blend(foreground, coverage);
+                device_ref.set_pixel(x as usize, y as usize, color)
+            }
+        });
+    }
+
+    pub fn render_character(&self, char: char) -> PixelMap {
+        let mut cache_ref = self.render_cache.borrow_mut();
+        match cache_ref.get(&char) {
+            Some(map) => map.clone(),
+            None => {
+                let pixel_map = self.font.rasterize(char);
+                cache_ref.insert(char, pixel_map);
+
+                // UNWRAP: we just inserted the bitmap into the cache if it wasn't there, so we know it exists
+                cache_ref.get(&char).unwrap().clone()
+            }
+        }
+    }
+}
+
+// SAFETY: Terminals will only exist as static references behind a mutex and will need to be locked for access
+unsafe impl<'a> Send for TerminalBackend<'a> {}
@@ -0,0 +1,157 @@
+use alloc::{boxed::Box, collections::LinkedList, string::String, vec::Vec};
+
+/// The command line being typed, with a cursor and a position in the command history.
+///
+/// This only keeps track of the text, drawing it is left to the terminal.
+#[derive(Default)]
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    // how many entries back up has gone into history, none while editing a fresh line
+    history_index: Option<usize>,
+    // the fresh line, put back once down goes past the newest history entry
+    draft: Vec<char>,
+}
+
+impl LineEditor {
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.line.iter().copied()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn insert(&mut self, char: char) {
+        self.line.insert(self.cursor, char);
+        self.cursor += 1;
+    }
+
+    /// Removes the character before the cursor.
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.line.remove(self.cursor);
+        }
+    }
+
+    /// Removes the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.line.len());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.line.len();
+    }
+
+    /// Moves to the start of the current word, or the previous one if already there.
+    pub fn word_left(&mut self) {
+        while self.cursor > 0 && self.line[self.cursor - 1].is_whitespace() {
+            self.cursor -= 1;
+        }
+        while self.cursor > 0 && !self.line[self.cursor - 1].is_whitespace() {
+            self.cursor -= 1;
+        }
+    }
+
+    /// Moves past the end of the current word, or the next one if already there.
+    pub fn word_right(&mut self) {
+        while self.cursor < self.line.len() && self.line[self.cursor].is_whitespace() {
+            self.cursor += 1;
+        }
+        while self.cursor < self.line.len() && !self.line[self.cursor].is_whitespace() {
+            self.cursor += 1;
+        }
+    }
+
+    /// Replaces the line with the next older history entry.
+    pub fn history_previous(&mut self, history: &LinkedList<Box<str>>) {
+        let index = self.history_index.map_or(0, |index| index + 1);
+        if let Some(entry) = history.iter().rev().nth(index) {
+            if self.history_index.is_none() {
+                self.draft = core::mem::take(&mut self.line);
+            }
+            self.history_index = Some(index);
+            self.replace(entry.chars().collect());
+        }
+    }
+
+    /// Replaces the line with the next newer history entry, or what was being typed before
+    /// browsing history.
+    pub fn history_next(&mut self, history: &LinkedList<Box<str>>) {
+        match self.history_index {
+            Some(0) | None => {
+                if self.history_index.take().is_some() {
+                    let draft = core::mem::take(&mut self.draft);
+                    self.replace(draft);
+                }
+            }
+            Some(index) => {
+                self.history_index = Some(index - 1);
+                // UNWRAP: we've already been further back than this entry
+                let entry = history.iter().rev().nth(index - 1).unwrap();
+                self.replace(entry.chars().collect());
+            }
+        }
+    }
+
+    /// Hands out the finished line and starts a fresh one.
+    pub fn take(&mut self) -> String {
+        let line = self.line.drain(..).collect();
+        *self = Self::default();
+
+        line
+    }
+
+    fn replace(&mut self, line: Vec<char>) {
+        self.line = line;
+        self.cursor = self.line.len();
+    }
+}
+
+#[test_case]
+fn line_editor_edits_at_the_cursor() {
+    let mut editor = LineEditor::default();
+    "hello world".chars().for_each(|char| editor.insert(char));
+    editor.word_left();
+    editor.backspace();
+    editor.insert(',');
+    editor.insert(' ');
+    editor.end();
+    editor.insert('!');
+
+    assert_eq!(editor.take(), "hello, world!");
+    assert_eq!(editor.cursor(), 0);
+}
+
+#[test_case]
+fn line_editor_recalls_history() {
+    let mut history = LinkedList::new();
+    history.push_back("first".into());
+    history.push_back("second".into());
+
+    let mut editor = LineEditor::default();
+    editor.insert('x');
+    editor.history_previous(&history);
+    editor.history_previous(&history);
+    editor.history_previous(&history);
+    assert_eq!(editor.chars().collect::<String>(), "first");
+
+    editor.history_next(&history);
+    editor.history_next(&history);
+    assert_eq!(editor.chars().collect::<String>(), "x");
+}
@@ -0,0 +1,582 @@
+mod ansi;
+mod back_buffer;
+mod backend;
+mod editor;
+
+pub use backend::TerminalBackend;
+
+use crate::{
+    graphics::{Color, GraphicsDevice},
+    keyboard::{KeyCode, KeyEvent, KeyState},
+};
+use alloc::{borrow::ToOwned, rc::Rc, string::String, sync::Arc};
+use ansi::{Action, Csi, Parser, Style};
+use back_buffer::TerminalBackBuffer;
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+};
+use editor::LineEditor;
+use lazy_static::lazy_static;
+use rusttype::Point;
+use spin::Mutex;
+
+// How wide the bar drawn at the editing position is, in pixels
+const CARET_WIDTH: usize = 2;
+const DEFAULT_FONT_SIZE: usize = 28;
+/// Where the terminal's font lives on the ramdisk.
+pub const FONT_PATH: &str = "fonts/open-sans/OpenSans-Regular.ttf";
+
+lazy_static! {
+    static ref TERMINAL: Arc<Mutex<Option<Terminal<'static>>>> = Arc::new(Mutex::new(None));
+}
+
+pub fn initialize(graphics_device: Rc<RefCell<dyn GraphicsDevice>>) {
+    *TERMINAL.lock() = Some(Terminal::new(graphics_device, DEFAULT_FONT_SIZE));
+    clear();
+}
+
+#[doc(hidden)]
+pub fn print(args: fmt::Arguments) {
+    let mut terminal_ref = TERMINAL.lock();
+    let terminal = terminal_ref.as_mut().unwrap();
+
+    // Output goes above the command line, which is drawn again below it
+    terminal.leave_scrollback();
+    terminal.hide_line();
+    terminal.write_fmt(args).unwrap();
+    terminal.show_line();
+}
+
+/// Releases the terminal lock regardless of who is holding it.
+///
+/// This is only meant for the panic handler, where the holder will never get to run again.
+pub unsafe fn force_unlock() {
+    if TERMINAL.is_locked() {
+        TERMINAL.force_unlock();
+    }
+}
+
+pub fn is_initialized() -> bool {
+    TERMINAL.lock().is_some()
+}
+
+/// Sets the prompt in front of the command line, showing the line editor if it wasn't already.
+pub fn set_prompt(prompt: &str) {
+    let mut terminal_ref = TERMINAL.lock();
+    let terminal = terminal_ref.as_mut().unwrap();
+
+    terminal.leave_scrollback();
+    terminal.hide_line();
+    terminal.prompt = prompt.to_owned();
+    terminal.line_visible = true;
+    terminal.show_line();
+}
+
+/// Feeds a key event from the keyboard into the command being typed, returning the command once
+/// it's submitted.
+pub fn handle_key(event: KeyEvent) -> Option<String> {
+    TERMINAL.lock().as_mut()?.handle_key(event)
+}
+
+#[doc(hidden)]
+pub fn clear() {
+    TERMINAL.lock().as_ref().unwrap().clear();
+}
+
+#[macro_export]
+macro_rules! clear {
+    () => {
+        $crate::terminal::clear()
+    };
+}
+
+pub struct Terminal<'a> {
+    size: Point<usize>,
+    cursor: Point<usize>,
+    background: Color,
+    foreground: Color,
+    font_size: usize,
+    backend: TerminalBackend<'a>,
+
+    prompt: String,
+    command: LineEditor,
+    back_buffer: TerminalBackBuffer,
+    // Where the prompt starts and where the last glyph of the command line ended, so the line
+    // can be erased and drawn again after every edit
+    line_start: Point<usize>,
+    line_end: Point<usize>,
+    line_visible: bool,
+    // Output since the last newline, which isn't in the back buffer yet
+    pending_line: String,
+    // How many lines back the scrollback viewer is showing, and where the cursor was on the live
+    // screen before it took over
+    scrollback: Option<usize>,
+    live_cursor: Point<usize>,
+    // Escape sequence handling, see `ansi`
+    parser: Parser,
+    style: Style,
+    saved_cursor: Option<(Point<usize>, Style)>,
+}
+
+impl<'a> Terminal<'a> {
+    pub fn new(device: Rc<RefCell<dyn GraphicsDevice>>, font_size: usize) -> Self {
+        let device_ref = device.borrow();
+        let cursor = Point { x: 0, y: 0 };
+        let size = Point {
+            x: device_ref.width(),
+            y: device_ref.height(),
+        };
+
+        drop(device_ref);
+        let backend = TerminalBackend::new(device, font_size);
+
+        Self {
+            size,
+            cursor,
+            background: Color::Black,
+            foreground: Color::White,
+            font_size,
+            backend,
+
+            prompt: "".to_owned(),
+            command: LineEditor::default(),
+            back_buffer: TerminalBackBuffer::default(),
+            line_start: cursor,
+            line_end: cursor,
+            line_visible: false,
+            pending_line: String::new(),
+            scrollback: None,
+            live_cursor: cursor,
+            parser: Parser::new(),
+            style: Style::new(Color::White, Color::Black),
+            saved_cursor: None,
+        }
+    }
+
+    pub fn clear(&self) {
+        self.backend.clear(self.background);
+    }
+
+    fn line_height(&self) -> usize {
+        self.backend.font.height()
+    }
+
+    fn newline(&mut self) {
+        let line_height = self.line_height();
+        self.cursor.x = 0;
+        self.cursor.y += line_height;
+
+        if self.cursor.y + line_height > self.size.y {
+            self.backend.scroll_up(line_height, self.background);
+            self.cursor.y -= line_height;
+            self.line_start.y = self.line_start.y.saturating_sub(line_height);
+        }
+    }
+
+    fn put_char(&mut self, char: char) {
+        match char {
+            '\n' => self.newline(),
+            _ => {
+                let pixel_map = self.backend.render_character(char);
+                let glyph_width = pixel_map.dimensions.x;
+                if self.cursor.x + glyph_width > self.size.x {
+                    self.newline();
+                }
+
+                let (foreground, background) = self.style.colors();
+                let Point { x, y } = self.cursor;
+                self.backend
+                    .fill_rect(x, y, glyph_width, self.line_height(), background);
+                self.backend
+                    .write_character(&pixel_map, x as i32, y as i32, foreground, background);
+                // there's only the one font, so bold is faked by drawing the glyph twice
+                if self.style.bold {
+                    self.backend.write_character(
+                        &pixel_map,
+                        x as i32 + 1,
+                        y as i32,
+                        foreground,
+                        background,
+                    );
+                }
+                self.cursor.x += glyph_width;
+            }
+        }
+    }
+
+    fn handle_key(&mut self, event: KeyEvent) -> Option<String> {
+        if event.state != KeyState::Pressed {
+            return None;
+        }
+
+        let page = (self.size.y / self.line_height()).saturating_sub(1).max(1);
+        match event.code {
+            KeyCode::PageUp if event.modifiers.shift => {
+                self.scroll_back(page);
+                return None;
+            }
+            KeyCode::PageDown if event.modifiers.shift => {
+                self.scroll_forward(page);
+                return None;
+            }
+            _ if !self.line_visible => return None,
+            _ => {}
+        }
+
+        let control = event.modifiers.control;
+        let history = &self.back_buffer.history.1;
+        match event.code {
+            KeyCode::Enter | KeyCode::NumpadEnter => return Some(self.submit()),
+            KeyCode::Backspace => self.command.backspace(),
+            KeyCode::Delete => self.command.delete(),
+            KeyCode::ArrowLeft if control => self.command.word_left(),
+            KeyCode::ArrowRight if control => self.command.word_right(),
+            KeyCode::ArrowLeft => self.command.left(),
+            KeyCode::ArrowRight => self.command.right(),
+            KeyCode::Home => self.command.home(),
+            KeyCode::End => self.command.end(),
+            KeyCode::ArrowUp => self.command.history_previous(history),
+            KeyCode::ArrowDown => self.command.history_next(history),
+            _ => match event.character {
+                Some(char) if !control && !char.is_control() => self.command.insert(char),
+                _ => return None,
+            },
+        }
+
+        // Typing while scrolled back jumps back to the command line
+        match self.scrollback {
+            Some(_) => self.leave_scrollback(),
+            None => {
+                self.hide_line();
+                self.show_line();
+            }
+        }
+
+        None
+    }
+
+    // Leaves the finished line on screen and starts a new one below it, returning the command
+    fn submit(&mut self) -> String {
+        self.hide_line();
+        self.draw_line(false);
+        self.newline();
+
+        // Anything printed on the same line before the prompt belongs to the same back buffer line
+        let prompt = core::mem::take(&mut self.pending_line) + &self.prompt;
+        let command = self.command.take();
+        self.back_buffer.push_command(prompt, command.clone());
+        self.show_line();
+
+        command
+    }
+
+    fn show_line(&mut self) {
+        if self.line_visible {
+            self.line_start = self.cursor;
+            self.draw_line(true);
+        }
+    }
+
+    // Erases the command line, leaving the cursor where it started
+    fn hide_line(&mut self) {
+        if !self.line_visible {
+            return;
+        }
+
+        let line_height = self.line_height();
+        let mut y = self.line_start.y;
+        let mut x = self.line_start.x;
+        while y <= self.line_end.y {
+            self.backend
+                .fill_rect(x, y, self.size.x - x, line_height, self.background);
+            x = 0;
+            y += line_height;
+        }
+        self.cursor = self.line_start;
+    }
+
+    fn draw_line(&mut self, caret: bool) {
+        let prompt = core::mem::take(&mut self.prompt);
+        prompt.chars().for_each(|char| self.put_char(char));
+        self.prompt = prompt;
+
+        let command = core::mem::take(&mut self.command);
+        let mut caret_position = None;
+        command.chars().enumerate().for_each(|(i, char)| {
+            if i == command.cursor() {
+                caret_position = Some(self.cursor);
+            }
+            self.put_char(char);
+        });
+        self.command = command;
+
+        self.line_end = self.cursor;
+        if caret {
+            let Point { x, y } = caret_position.unwrap_or(self.cursor);
+            let line_height = self.line_height();
+            self.backend
+                .fill_rect(x, y, CARET_WIDTH, line_height, self.foreground);
+        }
+    }
+}
+
+impl<'a> Terminal<'a> {
+    fn scroll_back(&mut self, lines: usize) {
+        let line_count = self.back_buffer.buffer.1.len();
+        let offset = (self.scrollback.unwrap_or(0) + lines).min(line_count.saturating_sub(1));
+        if offset == 0 {
+            return;
+        }
+
+        if self.scrollback.is_none() {
+            self.hide_line();
+            self.live_cursor = self.cursor;
+        }
+        self.scrollback = Some(offset);
+        self.draw_scrollback(offset);
+    }
+
+    fn scroll_forward(&mut self, lines: usize) {
+        match self.scrollback {
+            Some(offset) if offset > lines => {
+                self.scrollback = Some(offset - lines);
+                self.draw_scrollback(offset - lines);
+            }
+            Some(_) => self.leave_scrollback(),
+            None => {}
+        }
+    }
+
+    // Puts the live screen back together from the back buffer, anchored where it was left
+    fn leave_scrollback(&mut self) {
+        if self.scrollback.take().is_none() {
+            return;
+        }
+
+        self.clear();
+        let pending_line = core::mem::take(&mut self.pending_line);
+        let pending_rows = self.rows_for(&pending_line) - 1;
+        let top = self
+            .live_cursor
+            .y
+            .saturating_sub(pending_rows * self.line_height());
+
+        self.draw_lines_above(self.back_buffer.buffer.1.len(), top);
+        self.cursor = Point { x: 0, y: top };
+        pending_line.chars().for_each(|char| self.put_char(char));
+        self.pending_line = pending_line;
+        self.show_line();
+    }
+
+    fn draw_scrollback(&mut self, offset: usize) {
+        let line_height = self.line_height();
+        let bottom = self.size.y / line_height * line_height;
+
+        self.clear();
+        self.draw_lines_above(self.back_buffer.buffer.1.len() - offset, bottom);
+    }
+
+    // Draws back buffer lines before `end`, newest last, so they finish just above `bottom`
+    fn draw_lines_above(&mut self, end: usize, mut bottom: usize) {
+        // the back buffer only keeps text, so it's all drawn in the default style
+        let default = self.default_style();
+        let style = core::mem::replace(&mut self.style, default);
+        let line_height = self.line_height();
+        for index in (0..end).rev() {
+            let line = self.back_buffer.buffer.1[index].clone();
+            let height = self.rows_for(&line) * line_height;
+            if height > bottom {
+                break;
+            }
+
+            bottom -= height;
+            self.cursor = Point { x: 0, y: bottom };
+            line.chars().for_each(|char| self.put_char(char));
+        }
+        self.style = style;
+    }
+
+    // How many rows a line takes up once it's wrapped
+    fn rows_for(&self, line: &str) -> usize {
+        let mut x = 0;
+        let mut rows = 1;
+        for char in line.chars() {
+            let glyph_width = self.backend.render_character(char).dimensions.x;
+            if x + glyph_width > self.size.x {
+                x = 0;
+                rows += 1;
+            }
+            x += glyph_width;
+        }
+
+        rows
+    }
+
+    // Keeps printed output in the back buffer, one entry per line
+    fn record(&mut self, char: char) {
+        match char {
+            '\n' => {
+                let line = core::mem::take(&mut self.pending_line);
+                self.back_buffer.push_line(line.into_boxed_str());
+            }
+            _ => self.pending_line.push(char),
+        }
+    }
+}
+
+impl<'a> Terminal<'a> {
+    fn default_style(&self) -> Style {
+        Style::new(self.foreground, self.background)
+    }
+
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::Print(char) | Action::Execute(char @ '\n') => {
+                self.record(char);
+                self.put_char(char);
+            }
+            Action::Execute('\r') => self.cursor.x = 0,
+            Action::Execute('\t') => {
+                let tab_width = self.cell_width() * 8;
+                let x = (self.cursor.x / tab_width + 1) * tab_width;
+                self.cursor.x = x.min(self.size.x);
+            }
+            Action::Execute(_) => {}
+            Action::SaveCursor => self.saved_cursor = Some((self.cursor, self.style)),
+            Action::RestoreCursor => {
+                if let Some((cursor, style)) = self.saved_cursor {
+                    self.cursor = cursor;
+                    self.style = style;
+                }
+            }
+            Action::Reset => {
+                self.style = self.default_style();
+                self.saved_cursor = None;
+                self.clear();
+                self.cursor = Point { x: 0, y: 0 };
+            }
+            Action::Csi(csi) if !csi.private => self.perform_csi(csi),
+            Action::Csi(_) => {}
+        }
+    }
+
+    fn perform_csi(&mut self, csi: Csi) {
+        let line_height = self.line_height();
+        let cell_width = self.cell_width();
+        let last_row = (self.size.y / line_height).saturating_sub(1) * line_height;
+        let count = csi.param(0, 1) as usize;
+
+        match csi.function {
+            'm' => {
+                let default = self.default_style();
+                self.style.select_graphic_rendition(csi.params(), default);
+            }
+            'A' => self.cursor.y = self.cursor.y.saturating_sub(count * line_height),
+            'B' => self.cursor.y = (self.cursor.y + count * line_height).min(last_row),
+            'C' => self.cursor.x = (self.cursor.x + count * cell_width).min(self.size.x),
+            'D' => self.cursor.x = self.cursor.x.saturating_sub(count * cell_width),
+            'E' => {
+                self.cursor.x = 0;
+                self.cursor.y = (self.cursor.y + count * line_height).min(last_row);
+            }
+            'F' => {
+                self.cursor.x = 0;
+                self.cursor.y = self.cursor.y.saturating_sub(count * line_height);
+            }
+            'G' => self.cursor.x = ((count - 1) * cell_width).min(self.size.x),
+            'd' => self.cursor.y = ((count - 1) * line_height).min(last_row),
+            'H' | 'f' => {
+                let row = csi.param(0, 1) as usize - 1;
+                let column = csi.param(1, 1) as usize - 1;
+                self.cursor = Point {
+                    x: (column * cell_width).min(self.size.x),
+                    y: (row * line_height).min(last_row),
+                };
+            }
+            'K' => self.erase_line(csi.param(0, 0)),
+            'J' => self.erase_display(csi.param(0, 0)),
+            's' => self.saved_cursor = Some((self.cursor, self.style)),
+            'u' => {
+                if let Some((cursor, _)) = self.saved_cursor {
+                    self.cursor = cursor;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 0 erases from the cursor to the end of the line, 1 from the start to the cursor and 2 all of it
+    fn erase_line(&self, mode: u16) {
+        let Point { x, y } = self.cursor;
+        let (start, end) = match mode {
+            0 => (x, self.size.x),
+            1 => (0, x),
+            2 => (0, self.size.x),
+            _ => return,
+        };
+
+        self.backend.fill_rect(
+            start,
+            y,
+            end - start,
+            self.line_height(),
+            self.style.background,
+        );
+    }
+
+    // Like `erase_line`, but everything below or above the cursor's line goes with it
+    fn erase_display(&self, mode: u16) {
+        let line_height = self.line_height();
+        let y = self.cursor.y;
+        let background = self.style.background;
+        match mode {
+            0 => {
+                self.erase_line(0);
+                self.backend
+                    .fill_rect(0, y + line_height, self.size.x, self.size.y, background);
+            }
+            1 => {
+                self.erase_line(1);
+                self.backend.fill_rect(0, 0, self.size.x, y, background);
+            }
+            2 | 3 => self.backend.clear(background),
+            _ => {}
+        }
+    }
+
+    // Columns don't mean much with a proportional font, cursor movement goes by the width of a
+    // digit instead
+    fn cell_width(&self) -> usize {
+        self.backend.render_character('0').dimensions.x.max(1)
+    }
+}
+
+impl<'a> core::fmt::Write for Terminal<'a> {
+    fn write_fmt(mut self: &mut Self, args: fmt::Arguments<'_>) -> fmt::Result {
+        fmt::write(&mut self, args)
+    }
+
+    fn write_str(&mut self, data: &str) -> fmt::Result {
+        data.chars().try_for_each(|char| self.write_char(char))
+    }
+
+    fn write_char(&mut self, char: char) -> fmt::Result {
+        if let Some(action) = self.parser.advance(char) {
+            self.perform(action);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Span<T: PartialEq + PartialOrd> {
+    pub start: T,
+    pub end: T,
+}
+
+impl<T: PartialEq + PartialOrd> Span<T> {
+    pub fn new(start: T, end: T) -> Self {
+        Self { start, end }
+    }
+}
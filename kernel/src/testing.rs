@@ -0,0 +1,236 @@
+use crate::{idt, serial, serial_print, serial_println};
+use core::{
+    arch::global_asm,
+    panic::PanicInfo,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::instructions::{interrupts, port::Port};
+
+// Where QEMU's isa-debug-exit device is wired up, see `boot/main.rs`
+const EXIT_PORT: u16 = 0xf4;
+
+/// What the kernel tells QEMU to exit with. QEMU's own exit status is `(code << 1) | 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Exits QEMU, once everything queued for the serial port has been sent.
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    serial::flush();
+    unsafe { Port::<u32>::new(EXIT_PORT).write(code as u32) };
+
+    // only reached when there's no exit device to take us down
+    crate::halt_loop()
+}
+
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+
+    fn should_panic(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Fn()> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+/// A test that only passes if it panics, see `should_panic!`.
+pub struct ShouldPanic {
+    pub name: &'static str,
+    pub test: fn(),
+}
+
+impl Testable for ShouldPanic {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.test)()
+    }
+
+    fn should_panic(&self) -> bool {
+        true
+    }
+}
+
+/// Declares a test that passes only if its body panics.
+#[macro_export]
+macro_rules! should_panic {
+    (fn $name:ident() $body:block) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        const $name: $crate::testing::ShouldPanic = $crate::testing::ShouldPanic {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            test: {
+                fn $name() $body
+                $name
+            },
+        };
+    };
+}
+
+/// How a test stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Outcome {
+    Returned = 1,
+    Panicked = 2,
+}
+
+// The callee saved registers, stack pointer and return address of a `save_context` call
+#[repr(C)]
+struct Context {
+    registers: [u64; 8],
+}
+
+global_asm!(
+    ".global testing_save_context",
+    "testing_save_context:",
+    "mov [rdi], rbx",
+    "mov [rdi + 8], rbp",
+    "mov [rdi + 16], r12",
+    "mov [rdi + 24], r13",
+    "mov [rdi + 32], r14",
+    "mov [rdi + 40], r15",
+    "lea rdx, [rsp + 8]",
+    "mov [rdi + 48], rdx",
+    "mov rdx, [rsp]",
+    "mov [rdi + 56], rdx",
+    "xor eax, eax",
+    "ret",
+    ".global testing_restore_context",
+    "testing_restore_context:",
+    "mov rbx, [rdi]",
+    "mov rbp, [rdi + 8]",
+    "mov r12, [rdi + 16]",
+    "mov r13, [rdi + 24]",
+    "mov r14, [rdi + 32]",
+    "mov r15, [rdi + 40]",
+    "mov rsp, [rdi + 48]",
+    "mov rax, rsi",
+    "jmp [rdi + 56]",
+);
+
+extern "sysv64" {
+    // Returns 0 when called, and again with the value passed to `testing_restore_context`
+    fn testing_save_context(context: *mut Context) -> u64;
+    fn testing_restore_context(context: *const Context, value: u64) -> !;
+}
+
+static mut RESUME_CONTEXT: Context = Context { registers: [0; 8] };
+static TEST_RUNNING: AtomicBool = AtomicBool::new(false);
+static EXPECTING_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Runs every test, reporting each one over serial, and exits QEMU with the overall result.
+pub fn run(tests: &[&dyn Testable]) -> ! {
+    serial_println!("running {} tests", tests.len());
+    #[cfg(debug_assertions)]
+    let checkpoint = crate::mem::heap::diagnostics::checkpoint();
+
+    let mut failed = 0;
+    for test in tests {
+        serial_print!("{} ... ", test.name());
+        EXPECTING_PANIC.store(test.should_panic(), Ordering::Relaxed);
+
+        let interrupts_enabled = interrupts::are_enabled();
+        let outcome = run_one(*test);
+        // a test that bailed out of an interrupt handler, or swapped the IDT, mustn't break the
+        // ones after it
+        idt::load();
+        if interrupts_enabled {
+            interrupts::enable();
+        }
+
+        let passed = match test.should_panic() {
+            false => outcome == Outcome::Returned,
+            true => outcome == Outcome::Panicked,
+        };
+        match passed {
+            true => serial_println!("[ok]"),
+            false => {
+                failed += 1;
+                serial_println!("[failed]");
+            }
+        }
+    }
+
+    serial_println!("{} passed, {failed} failed", tests.len() - failed);
+    // not a failure, some tests leak on purpose
+    #[cfg(debug_assertions)]
+    serial_println!(
+        "left allocated by tests: {}",
+        crate::mem::heap::diagnostics::leaks_since(checkpoint)
+    );
+    match failed {
+        0 => exit_qemu(QemuExitCode::Success),
+        _ => exit_qemu(QemuExitCode::Failed),
+    }
+}
+
+// Kept out of line so nothing the caller has in callee saved registers is live across the jump
+// back in
+#[inline(never)]
+fn run_one(test: &dyn Testable) -> Outcome {
+    let resumed = unsafe { testing_save_context(ptr::addr_of_mut!(RESUME_CONTEXT)) };
+    match resumed {
+        0 => {}
+        1 => return Outcome::Returned,
+        _ => return Outcome::Panicked,
+    }
+
+    TEST_RUNNING.store(true, Ordering::SeqCst);
+    test.run();
+    TEST_RUNNING.store(false, Ordering::SeqCst);
+
+    Outcome::Returned
+}
+
+/// Abandons the running test and goes back to the runner, for tests that end somewhere they
+/// can't return from, like an exception handler.
+///
+/// Whatever the test had on its stack is never dropped, and any locks it held stay held.
+pub fn leave_test(outcome: Outcome) -> ! {
+    if !TEST_RUNNING.swap(false, Ordering::SeqCst) {
+        serial_println!("[failed]\nleft a test while none was running");
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    unsafe { testing_restore_context(ptr::addr_of!(RESUME_CONTEXT), outcome as u64) }
+}
+
+/// The panic handler for test builds, a panicking test fails (or passes, if it should panic) and
+/// the rest still run.
+pub fn panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    // the panicking code may have been printing
+    unsafe { serial::force_unlock() };
+
+    if !EXPECTING_PANIC.load(Ordering::Relaxed) {
+        serial_println!("\n{info}");
+    }
+    if TEST_RUNNING.load(Ordering::SeqCst) {
+        leave_test(Outcome::Panicked);
+    }
+
+    serial_println!("[failed]");
+    exit_qemu(QemuExitCode::Failed)
+}
+
+should_panic! {
+    fn failed_assertion_panics() {
+        assert_eq!(1, 2);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::idt;
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::x86_64::_rdtsc,
+    hint, mem,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+/// How often the system timer ticks.
+pub const TICK_HZ: u64 = 1000;
+
+const PIT_HZ: u64 = 1_193_182;
+const PIT_IRQ: u8 = 0;
+const CALIBRATION_MS: u64 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    pending: Vec::new(),
+    running: None,
+});
+
+/// Calibrates the TSC against the PIT, then starts the PIT ticking at `TICK_HZ` on IRQ 0.
+///
+/// This must be called with interrupts disabled, calibration polls the PIT and must not be
+/// interrupted.
+pub fn initialize() {
+    let tsc_hz = calibrate_tsc();
+    TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+
+    let divisor = (PIT_HZ / TICK_HZ) as u16;
+    unsafe {
+        // channel 0, low then high byte, mode 2 (rate generator)
+        Port::<u8>::new(0x43).write(0x34);
+        let mut channel_0 = Port::<u8>::new(0x40);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+
+    idt::register_irq(PIT_IRQ, tick);
+}
+
+/// Returns the number of timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns how long the system has been running, with `1 / TICK_HZ` second resolution.
+pub fn uptime() -> Duration {
+    Duration::from_millis(ticks() * 1000 / TICK_HZ)
+}
+
+/// Busy waits for at least `duration`.
+///
+/// The calibrated TSC gives this sub-tick precision, without it this falls back to waiting on
+/// timer ticks and needs interrupts enabled.
+pub fn sleep(duration: Duration) {
+    let tsc_hz = TSC_HZ.load(Ordering::Relaxed);
+    if tsc_hz == 0 {
+        let deadline = ticks() + duration_to_ticks(duration);
+        while ticks() < deadline {
+            hint::spin_loop();
+        }
+
+        return;
+    }
+
+    let cycles = duration.as_nanos() * tsc_hz as u128 / 1_000_000_000;
+    let deadline = unsafe { _rdtsc() } + cycles as u64;
+    while unsafe { _rdtsc() } < deadline {
+        hint::spin_loop();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Timers {
+    // sorted by descending deadline, so the next timer due is always at the end
+    pending: Vec<Timer>,
+    // the periodic timer whose callback is running, and whether it's been cancelled since
+    running: Option<(TimerId, bool)>,
+}
+
+struct Timer {
+    id: TimerId,
+    deadline: u64,
+    period: Option<u64>,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+/// Runs `callback` once after `delay`.
+///
+/// Callbacks run in interrupt context with interrupts disabled, so they must be short and must
+/// not wait on anything the interrupted code could be holding.
+pub fn after(delay: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    schedule(duration_to_ticks(delay), None, Box::new(callback))
+}
+
+/// Runs `callback` every `period`, starting one period from now. See `after` for the constraints
+/// on callbacks.
+pub fn every(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    let period = duration_to_ticks(period).max(1);
+
+    schedule(period, Some(period), Box::new(callback))
+}
+
+/// Cancels a pending timer, returning whether it was still scheduled. A periodic timer can
+/// cancel itself from its own callback.
+pub fn cancel(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        if let Some((running, cancelled)) = &mut timers.running {
+            if *running == id {
+                return !mem::replace(cancelled, true);
+            }
+        }
+
+        match timers.pending.iter().position(|timer| timer.id == id) {
+            Some(index) => {
+                timers.pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+fn schedule(delay: u64, period: Option<u64>, callback: Box<dyn FnMut() + Send>) -> TimerId {
+    let id = TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    let timer = Timer {
+        id,
+        deadline: ticks() + delay,
+        period,
+        callback,
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        // a periodic timer goes back in the slot it left when it ran, the spare slot kept here
+        // makes sure there's still room if its callback scheduled another timer in the meantime,
+        // so the tick handler never has to allocate
+        timers.pending.reserve(2);
+        insert_sorted(&mut timers.pending, timer);
+    });
+
+    id
+}
+
+fn insert_sorted(timers: &mut Vec<Timer>, timer: Timer) {
+    let index = timers.partition_point(|other| other.deadline > timer.deadline);
+    timers.insert(index, timer);
+}
+
+fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    loop {
+        let mut timers = TIMERS.lock();
+        let mut timer = match timers.pending.last() {
+            Some(timer) if timer.deadline <= now => timers.pending.pop().unwrap(),
+            _ => break,
+        };
+        timers.running = timer.period.map(|_| (timer.id, false));
+
+        // callbacks are free to schedule or cancel timers, their own included
+        drop(timers);
+        (timer.callback)();
+
+        let mut timers = TIMERS.lock();
+        let cancelled = matches!(timers.running.take(), Some((_, true)));
+        if let (Some(period), false) = (timer.period, cancelled) {
+            timer.deadline = now + period;
+            insert_sorted(&mut timers.pending, timer);
+        }
+    }
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos() * TICK_HZ as u128;
+
+    ((nanos + 999_999_999) / 1_000_000_000) as u64
+}
+
+// Counts TSC cycles across a one-shot countdown on PIT channel 2, which can be polled through the
+// keyboard controller's port B without needing interrupts
+fn calibrate_tsc() -> u64 {
+    let divisor = (PIT_HZ * CALIBRATION_MS / 1000) as u16;
+    let mut port_b = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+
+    unsafe {
+        // gate channel 2 on, keep the speaker off
+        let value = port_b.read();
+        port_b.write((value & !0x02) | 0x01);
+
+        // channel 2, low then high byte, mode 0 (interrupt on terminal count)
+        command.write(0xb0);
+        channel_2.write(divisor as u8);
+        channel_2.write((divisor >> 8) as u8);
+
+        // restart the count by toggling the gate
+        let value = port_b.read();
+        port_b.write(value & !0x01);
+        port_b.write(value | 0x01);
+
+        let start = _rdtsc();
+        while port_b.read() & 0x20 == 0 {
+            hint::spin_loop();
+        }
+        let end = _rdtsc();
+
+        (end - start) * 1000 / CALIBRATION_MS
+    }
+}
+
+#[test_case]
+fn sleep_waits_at_least_the_requested_time() {
+    let start = uptime();
+    sleep(Duration::from_millis(20));
+
+    assert!(uptime() - start >= Duration::from_millis(19));
+}
+
+#[test_case]
+fn periodic_timer_can_cancel_itself() {
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+    static ID: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    let id = every(Duration::from_millis(1), || {
+        if RUNS.fetch_add(1, Ordering::Relaxed) + 1 == 3 {
+            assert!(cancel(TimerId(ID.load(Ordering::Relaxed))));
+        }
+    });
+    ID.store(id.0, Ordering::Relaxed);
+    sleep(Duration::from_millis(20));
+
+    assert_eq!(RUNS.load(Ordering::Relaxed), 3);
+    assert!(!cancel(id));
+}